@@ -0,0 +1,291 @@
+use anyhow::anyhow;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::fs::{self, File};
+use std::io::{Read, Seek};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Component, Path, PathBuf};
+
+// Bundles are recognised by their first bytes, locations have no extension
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZIP_MAGIC: &[u8] = b"PK\x03\x04";
+
+// The entrypoint has to be inside the bundle
+pub fn is_entrypoint(path: &str) -> bool {
+    !path.is_empty()
+        && Path::new(path)
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+}
+
+// Packs a directory as a tar.gz. The same files always give the same bytes,
+// so the digest of a bundle only changes with its content
+pub fn pack(dir: &Path) -> Result<Vec<u8>, anyhow::Error> {
+    let mut files = vec![];
+    find_files(dir, Path::new(""), &mut files)?;
+
+    let mut builder = tar::Builder::new(GzEncoder::new(vec![], Compression::default()));
+    for relative in files {
+        let path = dir.join(&relative);
+        let metadata = fs::metadata(&path)?;
+
+        let mut header = tar::Header::new_gnu();
+        header.set_size(metadata.len());
+        header.set_mtime(0);
+        header.set_mode(match metadata.permissions().mode() & 0o111 {
+            0 => 0o644,
+            _ => 0o755,
+        });
+        builder.append_data(&mut header, &relative, File::open(&path)?)?;
+    }
+
+    Ok(builder.into_inner()?.finish()?)
+}
+
+fn find_files(dir: &Path, relative: &Path, files: &mut Vec<PathBuf>) -> Result<(), anyhow::Error> {
+    let mut entries = vec![];
+    for entry in fs::read_dir(dir.join(relative))? {
+        entries.push(entry?.file_name());
+    }
+    entries.sort();
+
+    for name in entries {
+        // the history of the repository the job lives in is not needed to run it
+        if name == ".git" {
+            continue;
+        }
+
+        let path = relative.join(&name);
+        // links to files are packed as the file, following links to
+        // directories could loop forever
+        let metadata = fs::symlink_metadata(dir.join(&path))?;
+        if metadata.is_dir() {
+            find_files(dir, &path, files)?;
+        } else if metadata.file_type().is_symlink() && dir.join(&path).is_dir() {
+            return Err(anyhow!(
+                "{} is a link to a directory, bundles cannot have them",
+                path.display()
+            ));
+        } else {
+            files.push(path);
+        }
+    }
+
+    Ok(())
+}
+
+// Unpacks a tar.gz or zip bundle into `dir`, no entry is written outside of it
+pub fn unpack(archive: &Path, dir: &Path) -> Result<(), anyhow::Error> {
+    let mut file = File::open(archive)?;
+    let mut magic = [0; 4];
+    if file.read_exact(&mut magic).is_err() {
+        return Err(anyhow!("the bundle is not a tar.gz or zip file"));
+    }
+    file.rewind()?;
+
+    if magic.starts_with(GZIP_MAGIC) {
+        untar(file, dir)
+    } else if magic.starts_with(ZIP_MAGIC) {
+        unzip(file, dir)
+    } else {
+        Err(anyhow!("the bundle is not a tar.gz or zip file"))
+    }
+}
+
+// tar skips the entries outside of `dir`, they are rejected the same as in zips
+fn untar(file: File, dir: &Path) -> Result<(), anyhow::Error> {
+    let mut archive = tar::Archive::new(GzDecoder::new(file));
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if !entry.unpack_in(dir)? {
+            return Err(anyhow!(
+                "{} is outside of the bundle",
+                entry.path()?.display()
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+fn unzip(file: File, dir: &Path) -> Result<(), anyhow::Error> {
+    let mut archive = zip::ZipArchive::new(file)?;
+
+    for i in 0..archive.len() {
+        let mut entry = archive.by_index(i)?;
+        let path = match entry.enclosed_name() {
+            Some(path) => dir.join(path),
+            None => return Err(anyhow!("{} is outside of the bundle", entry.name())),
+        };
+
+        if entry.is_dir() {
+            fs::create_dir_all(&path)?;
+            continue;
+        }
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        std::io::copy(&mut entry, &mut File::create(&path)?)?;
+        if let Some(mode) = entry.unix_mode() {
+            fs::set_permissions(&path, fs::Permissions::from_mode(mode & 0o777))?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn write(dir: &Path, name: &str, content: &str) {
+        let path = dir.join(name);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, content).unwrap();
+    }
+
+    fn bundle(dir: &Path) {
+        write(dir, "run.sh", "./lib/helper.sh");
+        write(dir, "lib/helper.sh", "echo hello");
+        write(dir, "data.json", "{}");
+        fs::set_permissions(dir.join("run.sh"), fs::Permissions::from_mode(0o700)).unwrap();
+    }
+
+    #[test]
+    fn entrypoints_are_inside_the_bundle() {
+        assert!(is_entrypoint("run.sh"));
+        assert!(is_entrypoint("bin/run.sh"));
+        assert!(!is_entrypoint(""));
+        assert!(!is_entrypoint("../run.sh"));
+        assert!(!is_entrypoint("/bin/sh"));
+        assert!(!is_entrypoint("./run.sh"));
+    }
+
+    #[test]
+    fn pack_gives_the_same_bytes_for_the_same_files() {
+        let first = tempfile::tempdir().unwrap();
+        let second = tempfile::tempdir().unwrap();
+        bundle(first.path());
+        // written in another order, with other times and write permissions
+        write(second.path(), "data.json", "{}");
+        write(second.path(), "lib/helper.sh", "echo hello");
+        write(second.path(), "run.sh", "./lib/helper.sh");
+        fs::set_permissions(
+            second.path().join("run.sh"),
+            fs::Permissions::from_mode(0o755),
+        )
+        .unwrap();
+        // the history of the repository is left out
+        write(second.path(), ".git/HEAD", "ref: refs/heads/main");
+
+        let packed = pack(first.path()).unwrap();
+        assert_eq!(packed, pack(first.path()).unwrap());
+        assert_eq!(packed, pack(second.path()).unwrap());
+
+        write(first.path(), "data.json", "[]");
+        assert_ne!(packed, pack(first.path()).unwrap());
+    }
+
+    #[test]
+    fn pack_and_unpack_round_trip() {
+        let source = tempfile::tempdir().unwrap();
+        bundle(source.path());
+
+        let archive = tempfile::NamedTempFile::new().unwrap();
+        fs::write(archive.path(), pack(source.path()).unwrap()).unwrap();
+        let target = tempfile::tempdir().unwrap();
+        unpack(archive.path(), target.path()).unwrap();
+
+        let helper = fs::read_to_string(target.path().join("lib/helper.sh")).unwrap();
+        assert_eq!(helper, "echo hello");
+        let mode = fs::metadata(target.path().join("run.sh"))
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o755);
+        let mode = fs::metadata(target.path().join("data.json"))
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o644);
+    }
+
+    #[test]
+    fn pack_rejects_links_to_directories() {
+        let dir = tempfile::tempdir().unwrap();
+        bundle(dir.path());
+        // a loop, it would never end if it was followed
+        std::os::unix::fs::symlink(".", dir.path().join("lib/self")).unwrap();
+
+        assert!(pack(dir.path()).is_err());
+    }
+
+    #[test]
+    fn pack_follows_links_to_files() {
+        let dir = tempfile::tempdir().unwrap();
+        bundle(dir.path());
+        std::os::unix::fs::symlink("lib/helper.sh", dir.path().join("helper.sh")).unwrap();
+
+        let archive = tempfile::NamedTempFile::new().unwrap();
+        fs::write(archive.path(), pack(dir.path()).unwrap()).unwrap();
+        let target = tempfile::tempdir().unwrap();
+        unpack(archive.path(), target.path()).unwrap();
+
+        let helper = target.path().join("helper.sh");
+        assert!(!fs::symlink_metadata(&helper)
+            .unwrap()
+            .file_type()
+            .is_symlink());
+        assert_eq!(fs::read_to_string(helper).unwrap(), "echo hello");
+    }
+
+    #[test]
+    fn unpack_rejects_tar_entries_outside_of_the_dir() {
+        let mut header = tar::Header::new_gnu();
+        // set by hand, set_path does not accept `..`
+        header.as_gnu_mut().unwrap().name[..9].copy_from_slice(b"../escape");
+        header.set_size(1);
+        header.set_mode(0o644);
+        header.set_cksum();
+        let mut builder = tar::Builder::new(GzEncoder::new(vec![], Compression::default()));
+        builder.append(&header, &b"x"[..]).unwrap();
+
+        let root = tempfile::tempdir().unwrap();
+        let archive = root.path().join("bundle");
+        fs::write(&archive, builder.into_inner().unwrap().finish().unwrap()).unwrap();
+        let dir = root.path().join("dir");
+        fs::create_dir(&dir).unwrap();
+
+        assert!(unpack(&archive, &dir).is_err());
+        assert!(!root.path().join("escape").exists());
+    }
+
+    #[test]
+    fn unpack_rejects_zip_entries_outside_of_the_dir() {
+        let mut zip = zip::ZipWriter::new(std::io::Cursor::new(vec![]));
+        zip.start_file("../escape", zip::write::FileOptions::default())
+            .unwrap();
+        zip.write_all(b"x").unwrap();
+
+        let root = tempfile::tempdir().unwrap();
+        let archive = root.path().join("bundle");
+        fs::write(&archive, zip.finish().unwrap().into_inner()).unwrap();
+        let dir = root.path().join("dir");
+        fs::create_dir(&dir).unwrap();
+
+        assert!(unpack(&archive, &dir).is_err());
+        assert!(!root.path().join("escape").exists());
+    }
+
+    #[test]
+    fn unpack_rejects_other_files() {
+        let archive = tempfile::NamedTempFile::new().unwrap();
+        fs::write(archive.path(), "echo hello").unwrap();
+        let dir = tempfile::tempdir().unwrap();
+
+        assert!(unpack(archive.path(), dir.path()).is_err());
+    }
+}
@@ -0,0 +1,301 @@
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+// Used when the worker config does not set script_cache_size
+pub const DEFAULT_SIZE: u64 = 512 * 1024 * 1024;
+
+struct Entry {
+    size: u64,
+    // value of `ScriptCache::clock` when it was last used
+    last_used: u64,
+}
+
+#[derive(Clone, Copy, Default, Debug)]
+pub struct Metrics {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    pub entries: u64,
+    pub bytes: u64,
+}
+
+// Scripts and bundles downloaded by the worker, so executions of a job
+// whose script did not change neither download it again nor fail when the
+// storage is down. The least recently used are removed once the cache
+// is larger than `max_size`.
+pub struct ScriptCache {
+    dir: PathBuf,
+    max_size: u64,
+    entries: HashMap<String, Entry>,
+    size: u64,
+    clock: u64,
+    metrics: Metrics,
+}
+
+pub type SharedCache = Arc<Mutex<ScriptCache>>;
+
+impl ScriptCache {
+    // Picks up the scripts cached by a previous run of the worker
+    pub fn open(dir: PathBuf, max_size: u64) -> Self {
+        let mut cache = ScriptCache {
+            dir,
+            max_size,
+            entries: HashMap::new(),
+            size: 0,
+            clock: 0,
+            metrics: Metrics::default(),
+        };
+        if max_size == 0 {
+            return cache;
+        }
+
+        if let Err(error) = fs::create_dir_all(&cache.dir) {
+            println!("script cache disabled, {:?}: {:?}", cache.dir, error);
+            cache.max_size = 0;
+            return cache;
+        }
+
+        let mut found = vec![];
+        if let Ok(files) = fs::read_dir(&cache.dir) {
+            for file in files.flatten() {
+                let name = file.file_name().to_string_lossy().to_string();
+                match file.metadata() {
+                    // unfinished copies of a previous run
+                    _ if name.ends_with(".tmp") => {
+                        let _ = fs::remove_file(file.path());
+                    }
+                    Ok(metadata) if metadata.is_file() => {
+                        found.push((metadata.modified().ok(), name, metadata.len()))
+                    }
+                    _ => (),
+                }
+            }
+        }
+        // the oldest files are the first evicted
+        found.sort();
+        for (_, key, size) in found {
+            cache.add(key, size);
+        }
+        cache.evict(0);
+
+        cache
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.max_size > 0
+    }
+
+    pub fn metrics(&self) -> Metrics {
+        Metrics {
+            entries: self.entries.len() as u64,
+            bytes: self.size,
+            ..self.metrics
+        }
+    }
+
+    // Path of the cached copy, None on a miss
+    pub fn get(&mut self, key: &str) -> Option<PathBuf> {
+        self.clock += 1;
+        match self.entries.get_mut(key) {
+            Some(entry) => {
+                entry.last_used = self.clock;
+                self.metrics.hits += 1;
+                Some(self.dir.join(key))
+            }
+            None => {
+                self.metrics.misses += 1;
+                None
+            }
+        }
+    }
+
+    // Where a new copy is written before `insert` moves it into the cache,
+    // `id` keeps concurrent executions of the same job apart
+    pub fn temp_path(&self, key: &str, id: &str) -> PathBuf {
+        self.dir.join(format!("{}.{}.tmp", key, id))
+    }
+
+    // Moves the file at `temp_path` into the cache
+    pub fn insert(&mut self, key: &str, temp_path: &Path) -> Result<(), std::io::Error> {
+        let size = fs::metadata(temp_path)?.len();
+        if size > self.max_size {
+            return fs::remove_file(temp_path);
+        }
+
+        // replaced, not removed, the file is overwritten by the rename
+        if let Some(entry) = self.entries.remove(key) {
+            self.size -= entry.size;
+        }
+        fs::rename(temp_path, self.dir.join(key))?;
+        self.add(key.into(), size);
+        self.evict(size);
+        Ok(())
+    }
+
+    // A cached copy that turned out to be wrong, e.g. a digest mismatch
+    pub fn remove(&mut self, key: &str) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.size -= entry.size;
+            let _ = fs::remove_file(self.dir.join(key));
+        }
+    }
+
+    fn add(&mut self, key: String, size: u64) {
+        self.clock += 1;
+        self.size += size;
+        self.entries.insert(
+            key,
+            Entry {
+                size,
+                last_used: self.clock,
+            },
+        );
+    }
+
+    // Removes the least recently used entries until the cache fits, keeping
+    // the one just added (`kept` bytes at most)
+    fn evict(&mut self, kept: u64) {
+        while self.size > self.max_size && self.size > kept {
+            let oldest = self
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| key.clone());
+
+            match oldest {
+                Some(key) => {
+                    self.remove(&key);
+                    self.metrics.evictions += 1;
+                }
+                None => break,
+            }
+        }
+    }
+}
+
+// Name of the cached copy. The digest is part of it so a location that
+// gets new content is not served from the cache.
+pub fn key(location: &str, digest: &str) -> String {
+    format!("{:x}", Sha256::digest(format!("{}\n{}", location, digest)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn insert(cache: &mut ScriptCache, key: &str, size: usize) {
+        let temp_path = cache.temp_path(key, "1");
+        fs::write(&temp_path, vec![b'x'; size]).unwrap();
+        cache.insert(key, &temp_path).unwrap();
+    }
+
+    fn cached(cache: &ScriptCache) -> Vec<String> {
+        let mut keys: Vec<String> = cache.entries.keys().cloned().collect();
+        keys.sort();
+        keys
+    }
+
+    #[test]
+    fn evicts_the_least_recently_used() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut cache = ScriptCache::open(dir.path().to_path_buf(), 10);
+
+        insert(&mut cache, "a", 4);
+        insert(&mut cache, "b", 4);
+        assert!(cache.get("a").is_some());
+        insert(&mut cache, "c", 4);
+
+        assert_eq!(cached(&cache), vec!["a", "c"]);
+        assert!(cache.get("b").is_none());
+        assert!(!dir.path().join("b").exists());
+        assert_eq!(fs::read(cache.get("c").unwrap()).unwrap().len(), 4);
+
+        let metrics = cache.metrics();
+        assert_eq!(metrics.hits, 2);
+        assert_eq!(metrics.misses, 1);
+        assert_eq!(metrics.evictions, 1);
+        assert_eq!(metrics.entries, 2);
+        assert_eq!(metrics.bytes, 8);
+    }
+
+    #[test]
+    fn keeps_the_entry_just_added() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut cache = ScriptCache::open(dir.path().to_path_buf(), 10);
+
+        insert(&mut cache, "a", 4);
+        insert(&mut cache, "b", 4);
+        insert(&mut cache, "c", 10);
+
+        assert_eq!(cached(&cache), vec!["c"]);
+        assert_eq!(cache.metrics().evictions, 2);
+        assert_eq!(cache.metrics().bytes, 10);
+    }
+
+    #[test]
+    fn does_not_cache_what_does_not_fit() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut cache = ScriptCache::open(dir.path().to_path_buf(), 10);
+
+        insert(&mut cache, "a", 4);
+        insert(&mut cache, "b", 11);
+
+        assert_eq!(cached(&cache), vec!["a"]);
+        assert!(!cache.temp_path("b", "1").exists());
+        assert_eq!(cache.metrics().evictions, 0);
+    }
+
+    #[test]
+    fn replacing_an_entry_replaces_its_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut cache = ScriptCache::open(dir.path().to_path_buf(), 10);
+
+        insert(&mut cache, "a", 4);
+        insert(&mut cache, "a", 6);
+        insert(&mut cache, "b", 4);
+
+        assert_eq!(cached(&cache), vec!["a", "b"]);
+        assert_eq!(cache.metrics().bytes, 10);
+
+        cache.remove("a");
+        assert_eq!(cache.metrics().bytes, 4);
+        assert!(!dir.path().join("a").exists());
+    }
+
+    #[test]
+    fn open_picks_up_the_previous_files() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a"), vec![b'x'; 4]).unwrap();
+        fs::write(dir.path().join("b.exec.tmp"), vec![b'x'; 4]).unwrap();
+
+        let mut cache = ScriptCache::open(dir.path().to_path_buf(), 10);
+        assert_eq!(cached(&cache), vec!["a"]);
+        assert!(cache.get("a").is_some());
+        assert!(!dir.path().join("b.exec.tmp").exists());
+
+        // a smaller size evicts what no longer fits
+        insert(&mut cache, "b", 4);
+        let cache = ScriptCache::open(dir.path().to_path_buf(), 4);
+        assert_eq!(cache.metrics().entries, 1);
+        assert_eq!(cache.metrics().bytes, 4);
+    }
+
+    #[test]
+    fn a_size_of_zero_disables_the_cache() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = ScriptCache::open(dir.path().join("scripts"), 0);
+
+        assert!(!cache.enabled());
+        assert!(!dir.path().join("scripts").exists());
+    }
+
+    #[test]
+    fn keys_change_with_the_digest() {
+        assert_eq!(key("scripts/a", "1"), key("scripts/a", "1"));
+        assert_ne!(key("scripts/a", "1"), key("scripts/a", "2"));
+        assert_ne!(key("scripts/a", "1"), key("scripts/b", "1"));
+    }
+}
@@ -1,34 +1,160 @@
 extern crate clap;
 use clap::{App, Arg, ArgMatches, SubCommand};
 use dcron::public_client::PublicClient;
-use dcron::{DisableJobRequest, JobRequest, JobStatusRequest, ScriptType};
+use dcron::{
+    CancelExecutionRequest, DisableJobRequest, ExecutionStatus, GetExecutionRequest,
+    GetLogUrlRequest, GetScriptUrlRequest, JobRequest,
+    ListExecutionsRequest, JobStatusRequest, ListJobsRequest, PauseJobRequest,
+    ResumeJobRequest, Retention, ScriptChunk, ScriptType, TailExecutionRequest, UpdateJobRequest,
+    WatchRequest,
+};
+use manifest::Change;
 use once_cell::sync::OnceCell;
+use output::{Format, Outcome};
+use std::collections::{HashMap, HashSet};
 use std::env;
+use std::fs;
+use std::io::Write;
 use std::path::Path;
 use std::str::FromStr;
+use std::time::Duration;
+use storage::StoredScript;
+use tonic::transport::{Certificate, Channel, ClientTlsConfig, Endpoint, Identity};
+use tonic::Code;
 
-mod config;
-mod storage;
+use dcron_core::{bundle, config, storage};
+mod crontab;
+mod manifest;
+mod output;
 
 pub mod dcron {
     tonic::include_proto!("dcron");
 }
 
 static CONFIG: OnceCell<config::Config> = OnceCell::new();
+// Where the server is and how to reach it, from the options or the config
+static CONNECTION: OnceCell<config::Client> = OnceCell::new();
+
+const DEFAULT_SERVER: &str = "http://[::1]:50051";
+const DEFAULT_CONNECT_TIMEOUT: u64 = 5;
+const DEFAULT_TIMEOUT: u64 = 30;
+
+// Scripts are sent to UploadScript in chunks of this size
+const UPLOAD_CHUNK_SIZE: usize = 64 * 1024;
+
+// Exit codes, so scripts can tell the failures apart
+const EXIT_DIFFERENCES: i32 = 1;
+const EXIT_ERROR: i32 = 2;
+const EXIT_NOT_FOUND: i32 = 3;
+const EXIT_CONFLICT: i32 = 4;
+const EXIT_INVALID: i32 = 5;
+const EXIT_UNAVAILABLE: i32 = 6;
+
 // dcron-client create 1234 0 python test.py my_job
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let config_file = env::var("DCRON_CONFIG").unwrap_or("app.toml".into());
+async fn main() {
+    if let Err(error) = run().await {
+        // the differences were already printed, they are not an error
+        if !error.is::<Differences>() {
+            eprintln!("error: {}", describe(error.as_ref()));
+        }
+        std::process::exit(exit_code(error.as_ref()));
+    }
+}
+
+// Returned by diff when the server is not in sync with the manifests
+#[derive(Debug)]
+struct Differences;
 
-    let config = config::Config::from(&config_file);
+impl std::fmt::Display for Differences {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "the server differs from the manifests")
+    }
+}
+
+impl std::error::Error for Differences {}
+
+fn exit_code(error: &(dyn std::error::Error + 'static)) -> i32 {
+    if error.is::<Differences>() {
+        return EXIT_DIFFERENCES;
+    }
+
+    let code = match error.downcast_ref::<tonic::Status>() {
+        Some(status) => status.code(),
+        None => return EXIT_ERROR,
+    };
 
-    let config = config.expect("Error while trying to read configuration file");
+    match code {
+        Code::NotFound => EXIT_NOT_FOUND,
+        Code::AlreadyExists | Code::Aborted => EXIT_CONFLICT,
+        Code::InvalidArgument | Code::FailedPrecondition | Code::OutOfRange => EXIT_INVALID,
+        Code::Unavailable | Code::DeadlineExceeded => EXIT_UNAVAILABLE,
+        _ => EXIT_ERROR,
+    }
+}
 
-    CONFIG.set(config).expect("could not set configuration");
+async fn run() -> Result<(), Box<dyn std::error::Error>> {
+    let config = match env::var("DCRON_CONFIG") {
+        Ok(config_file) => config::Config::from(&config_file)?,
+        // the config is only needed to upload scripts or reach the server
+        Err(_) if !Path::new("app.toml").exists() => config::Config::default(),
+        Err(_) => config::Config::from("app.toml")?,
+    };
 
     let matches = App::new("dcron_client")
         .version("0.0.1")
         .author("Elias Granja <me@elias.sh>")
+        .arg(
+            Arg::with_name("server")
+                .long("server")
+                .value_name("URL")
+                .env("DCRON_SERVER")
+                .help("Server to talk to, https:// urls use TLS")
+                .takes_value(true)
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("ca_cert")
+                .long("ca-cert")
+                .value_name("PEM")
+                .help("CA used to verify the server certificate")
+                .takes_value(true)
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("client_cert")
+                .long("client-cert")
+                .value_name("PEM")
+                .help("Certificate sent to servers that require client authentication")
+                .requires("client_key")
+                .takes_value(true)
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("client_key")
+                .long("client-key")
+                .value_name("PEM")
+                .requires("client_cert")
+                .takes_value(true)
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("output")
+                .long("output")
+                .value_name("FORMAT")
+                .help("How results are printed")
+                .possible_values(&["table", "json", "yaml"])
+                .default_value("table")
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("timeout_seconds")
+                .long("request-timeout")
+                .value_name("SECONDS")
+                .help("Gives up on requests that take longer than this")
+                .takes_value(true)
+                .global(true),
+        )
         .subcommand(
             SubCommand::with_name("disable").about("Disable a job").arg(
                 Arg::with_name("name")
@@ -39,18 +165,208 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                     .required(true),
             ),
         )
+        .subcommand(SubCommand::with_name("list").about("Lists all active jobs"))
+        .subcommand(
+            SubCommand::with_name("pause")
+                .about("Skips the job executions until it is resumed")
+                .arg(
+                    Arg::with_name("name")
+                        .takes_value(true)
+                        .index(1)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("reason")
+                        .short("r")
+                        .long("reason")
+                        .value_name("REASON")
+                        .help("Recorded on every skipped execution")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("until")
+                        .short("u")
+                        .long("until")
+                        .value_name("RFC3339")
+                        .help("Resumes the job automatically after this time")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("resume").about("Resumes a paused job").arg(
+                Arg::with_name("name")
+                    .takes_value(true)
+                    .index(1)
+                    .required(true),
+            ),
+        )
+        .subcommand(
+            SubCommand::with_name("cancel")
+                .about("Kills a running execution")
+                .arg(
+                    Arg::with_name("execution_id")
+                        .takes_value(true)
+                        .index(1)
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("logs")
+                .about("Prints the log of the most recent execution of a job")
+                .arg(
+                    Arg::with_name("job")
+                        .takes_value(true)
+                        .index(1)
+                        .required_unless("execution"),
+                )
+                .arg(
+                    Arg::with_name("execution")
+                        .short("e")
+                        .long("execution")
+                        .value_name("EXECUTION_ID")
+                        .help("Prints this execution instead of the most recent one")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("follow")
+                        .short("f")
+                        .long("follow")
+                        .help("Keeps printing new lines until the execution finishes"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("executions")
+                .about("Lists the executions of a job, most recent first")
+                .arg(
+                    Arg::with_name("job")
+                        .takes_value(true)
+                        .index(1)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("status")
+                        .short("s")
+                        .long("status")
+                        .value_name("STATUS")
+                        .help("Only executions with this status, can be repeated")
+                        .possible_values(&[
+                            "running",
+                            "timeout",
+                            "failed",
+                            "succeeded",
+                            "skipped",
+                            "cancelled",
+                        ])
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1),
+                )
+                .arg(
+                    Arg::with_name("limit")
+                        .short("l")
+                        .long("limit")
+                        .value_name("LIMIT")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("page_token")
+                        .long("page-token")
+                        .value_name("TOKEN")
+                        .help("Token printed by the previous call to get the next page")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("execution")
+                .about("Shows a single execution")
+                .arg(
+                    Arg::with_name("id")
+                        .takes_value(true)
+                        .index(1)
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("log-url")
+                .about("Prints a url to download the log of an execution without credentials")
+                .arg(
+                    Arg::with_name("id")
+                        .takes_value(true)
+                        .index(1)
+                        .required(true),
+                )
+                .arg(ttl_option()),
+        )
+        .subcommand(
+            SubCommand::with_name("script-url")
+                .about("Prints a url to download the script of a job without credentials")
+                .arg(
+                    Arg::with_name("name")
+                        .takes_value(true)
+                        .index(1)
+                        .required(true),
+                )
+                .arg(ttl_option()),
+        )
+        .subcommand(
+            SubCommand::with_name("watch")
+                .about("Prints changes to jobs and executions as they happen")
+                .arg(
+                    Arg::with_name("job")
+                        .short("j")
+                        .long("job")
+                        .value_name("NAME")
+                        .help("Only events of this job")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("label")
+                        .long("label")
+                        .value_name("KEY=VALUE")
+                        .help("Only events of jobs with this label, can be repeated")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .validator(is_key_value),
+                )
+                .arg(
+                    Arg::with_name("cursor")
+                        .short("c")
+                        .long("cursor")
+                        .value_name("CURSOR")
+                        .help("Resumes after the event with this cursor")
+                        .takes_value(true),
+                ),
+        )
         .subcommand(
             SubCommand::with_name("get")
-                .about("Sets up a script to be run at a DCRON instance")
+                .about("Shows a job, its next runs and its most recent executions")
                 .arg(
-                    Arg::with_name("time")
-                        .short("t")
-                        .long("time")
-                        .value_name("CRON_SYNTAX")
-                        .help("Sets the frequence you want the job to be run")
-                        .required(true)
+                    Arg::with_name("name")
+                        .takes_value(true)
                         .index(1)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("runs")
+                        .long("runs")
+                        .value_name("COUNT")
+                        .help("How many of the next runs to show")
+                        .default_value("5")
                         .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("executions")
+                        .long("executions")
+                        .value_name("COUNT")
+                        .help("How many of the most recent executions to show")
+                        .default_value("5")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("script")
+                        .long("script")
+                        .help("Also prints the script, downloaded from the object storage"),
                 ),
         )
         .subcommand(
@@ -81,6 +397,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                         .short("p")
                         .long("type")
                         .value_name("TYPE")
+                        .possible_values(&["python", "ruby", "shell"])
                         .takes_value(true)
                         .index(3)
                         .required(true),
@@ -108,43 +425,862 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                         .takes_value(true)
                         .index(5)
                         .required(true),
+                )
+                .arg(entrypoint_option())
+                .args(&job_options()),
+        )
+        .subcommand(
+            SubCommand::with_name("update")
+                .about("Changes only the given fields of an existing job")
+                .arg(
+                    Arg::with_name("name")
+                        .takes_value(true)
+                        .index(1)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("time")
+                        .short("t")
+                        .long("time")
+                        .value_name("CRON_SYNTAX")
+                        .help("Sets the frequence you want the job to be run")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("timeout")
+                        .short("o")
+                        .long("timeout")
+                        .value_name("TIMEOUT")
+                        .help("Defines the timeout for the job, if zero no timeout will be set")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("type")
+                        .short("p")
+                        .long("type")
+                        .value_name("TYPE")
+                        .possible_values(&["python", "ruby", "shell"])
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("script")
+                        .short("s")
+                        .long("script")
+                        .help("Uploads the file and uses it as the new job script")
+                        .takes_value(true),
+                )
+                .arg(entrypoint_option().requires("script"))
+                .args(&job_options()),
+        )
+        .subcommand(
+            SubCommand::with_name("apply")
+                .about("Creates or updates the jobs described in manifest files")
+                .arg(
+                    Arg::with_name("file")
+                        .short("f")
+                        .long("file")
+                        .value_name("PATH")
+                        .help("A .toml/.yaml manifest or a directory with them")
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("prune")
+                        .long("prune")
+                        .help("Disables jobs created by apply that are no longer in the manifests"),
                 ),
         )
+        .subcommand(
+            SubCommand::with_name("diff")
+                .about("Shows what apply would change, exits with 1 if anything differs")
+                .arg(
+                    Arg::with_name("file")
+                        .short("f")
+                        .long("file")
+                        .value_name("PATH")
+                        .help("A .toml/.yaml manifest or a directory with them")
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("prune")
+                        .long("prune")
+                        .help("Also shows the jobs apply --prune would disable"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("import-crontab")
+                .about("Creates a shell job for every command of a crontab file")
+                .arg(
+                    Arg::with_name("file")
+                        .takes_value(true)
+                        .index(1)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("prefix")
+                        .long("prefix")
+                        .value_name("PREFIX")
+                        .help("Jobs are named PREFIX-ID, defaults to the file name")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("timeout")
+                        .short("o")
+                        .long("timeout")
+                        .value_name("TIMEOUT")
+                        .help("Defines the timeout for the jobs, if zero no timeout will be set")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("dry_run")
+                        .long("dry-run")
+                        .help("Prints the jobs without uploading or creating anything"),
+                )
+                .args(&job_options()),
+        )
         .get_matches();
 
+    let mut connection = config.client.clone().unwrap_or_default();
+    if let Some(server) = matches.value_of("server") {
+        connection.server = Some(server.into());
+    }
+    if let Some(ca_cert) = matches.value_of("ca_cert") {
+        connection.ca_cert = Some(ca_cert.into());
+    }
+    if let Some(client_cert) = matches.value_of("client_cert") {
+        connection.client_cert = Some(client_cert.into());
+        connection.client_key = matches.value_of("client_key").map(String::from);
+    }
+    if let Some(timeout) = matches.value_of("timeout_seconds") {
+        connection.timeout = Some(<u64 as FromStr>::from_str(timeout)?);
+    }
+
+    output::init(matches.value_of("output").unwrap_or("table"));
+
+    if CONFIG.set(config).is_err() {
+        return Err("the configuration was already set".into());
+    }
+    if CONNECTION.set(connection).is_err() {
+        return Err("the connection options were already set".into());
+    }
+
     if let Some(matches) = matches.subcommand_matches("create") {
         create_job(matches).await?;
     } else if let Some(matches) = matches.subcommand_matches("disable") {
         disable_job(matches).await?;
     } else if let Some(matches) = matches.subcommand_matches("get") {
         get_job(matches).await?;
+    } else if let Some(matches) = matches.subcommand_matches("update") {
+        update_job(matches).await?;
+    } else if matches.subcommand_matches("list").is_some() {
+        list_jobs().await?;
+    } else if let Some(matches) = matches.subcommand_matches("pause") {
+        pause_job(matches).await?;
+    } else if let Some(matches) = matches.subcommand_matches("resume") {
+        resume_job(matches).await?;
+    } else if let Some(matches) = matches.subcommand_matches("cancel") {
+        cancel_execution(matches).await?;
+    } else if let Some(matches) = matches.subcommand_matches("logs") {
+        tail_execution(matches).await?;
+    } else if let Some(matches) = matches.subcommand_matches("executions") {
+        list_executions(matches).await?;
+    } else if let Some(matches) = matches.subcommand_matches("execution") {
+        get_execution(matches).await?;
+    } else if let Some(matches) = matches.subcommand_matches("log-url") {
+        get_log_url(matches).await?;
+    } else if let Some(matches) = matches.subcommand_matches("script-url") {
+        get_script_url(matches).await?;
+    } else if let Some(matches) = matches.subcommand_matches("watch") {
+        watch(matches).await?;
+    } else if let Some(matches) = matches.subcommand_matches("apply") {
+        apply(matches).await?;
+    } else if let Some(matches) = matches.subcommand_matches("diff") {
+        diff(matches).await?;
+    } else if let Some(matches) = matches.subcommand_matches("import-crontab") {
+        import_crontab(matches).await?;
     }
 
     Ok(())
 }
 
+// Connects to the server given by --server, DCRON_SERVER or the client config
+async fn connect() -> Result<PublicClient<Channel>, Box<dyn std::error::Error>> {
+    let options = CONNECTION.get().cloned().unwrap_or_default();
+    let server = options.server.unwrap_or_else(|| DEFAULT_SERVER.into());
+
+    let endpoint = match Endpoint::from_shared(server.clone()) {
+        Ok(endpoint) => endpoint,
+        Err(_) => return Err(format!("invalid server url {}", server).into()),
+    };
+    let mut endpoint = endpoint
+        .connect_timeout(Duration::from_secs(
+            options.connect_timeout.unwrap_or(DEFAULT_CONNECT_TIMEOUT),
+        ))
+        .timeout(Duration::from_secs(
+            options.timeout.unwrap_or(DEFAULT_TIMEOUT),
+        ));
+
+    if server.starts_with("https://") || options.ca_cert.is_some() {
+        let mut tls = ClientTlsConfig::new();
+        if let Some(ca_cert) = &options.ca_cert {
+            tls = tls.ca_certificate(Certificate::from_pem(read_pem(ca_cert)?));
+        }
+        match (&options.client_cert, &options.client_key) {
+            (Some(cert), Some(key)) => {
+                tls = tls.identity(Identity::from_pem(read_pem(cert)?, read_pem(key)?))
+            }
+            (None, None) => (),
+            _ => return Err("client_cert and client_key must be set together".into()),
+        }
+        if let Some(domain) = &options.domain {
+            tls = tls.domain_name(domain);
+        }
+        endpoint = endpoint.tls_config(tls)?;
+    }
+
+    match endpoint.connect().await {
+        Ok(channel) => Ok(PublicClient::new(channel)),
+        Err(error) => Err(tonic::Status::unavailable(format!(
+            "could not connect to {}: {}",
+            server,
+            describe(&error)
+        ))
+        .into()),
+    }
+}
+
+fn read_pem(path: &str) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    match fs::read(path) {
+        Ok(pem) => Ok(pem),
+        Err(error) => Err(format!("could not read {}: {}", path, error).into()),
+    }
+}
+
+// Errors from tonic say little at the top level, the cause is in the sources
+fn describe(error: &(dyn std::error::Error + 'static)) -> String {
+    if let Some(status) = error.downcast_ref::<tonic::Status>() {
+        return format!("{} ({:?})", status.message(), status.code());
+    }
+
+    let mut message = error.to_string();
+    let mut source = error.source();
+    while let Some(cause) = source {
+        // hyper repeats the message of the cause in its own
+        let cause_message = cause.to_string();
+        if !message.contains(&cause_message) {
+            message.push_str(&format!(": {}", cause_message));
+        }
+        source = cause.source();
+    }
+    message
+}
+
 async fn get_job(matches: &ArgMatches<'_>) -> Result<(), Box<dyn std::error::Error>> {
-    let client = PublicClient::connect("http://[::1]:50051");
+    let mut client = connect().await?;
     let request = tonic::Request::new(JobStatusRequest {
         name: matches.value_of("name").unwrap().into(),
     });
 
-    let response = client.await.unwrap().get_job(request).await.unwrap();
+    let job = client.get_job(request).await?.into_inner();
+
+    let runs = <usize as FromStr>::from_str(matches.value_of("runs").unwrap())?;
+    let next_runs = match job_scheduler::Schedule::from_str(&job.time) {
+        Ok(schedule) => schedule
+            .upcoming(chrono::Utc)
+            .take(runs)
+            .map(|time| time.to_rfc3339())
+            .collect(),
+        // the server validates it, but older jobs may have been saved without it
+        Err(_) => vec![],
+    };
+
+    let executions = client
+        .list_executions(ListExecutionsRequest {
+            job: job.name.clone(),
+            page_size: <i32 as FromStr>::from_str(matches.value_of("executions").unwrap())?,
+            ..Default::default()
+        })
+        .await?
+        .into_inner()
+        .executions;
+
+    let script = match matches.is_present("script") {
+        true => match stored_script(&job.location).await? {
+            Some(script) => Some(String::from_utf8_lossy(&script).into_owned()),
+            None => return Err(format!("script {} not found", job.location).into()),
+        },
+        false => None,
+    };
+
+    output::details(&output::JobDetails {
+        job: output::Job::from(&job),
+        next_runs,
+        executions: executions.iter().map(output::Execution::from).collect(),
+        script,
+    })?;
+
+    Ok(())
+}
+
+async fn list_jobs() -> Result<(), Box<dyn std::error::Error>> {
+    let mut client = connect().await?;
+    let request = tonic::Request::new(ListJobsRequest {});
+
+    let jobs = client.list_jobs(request).await?.into_inner().jobs;
 
-    println!("RESPONSE={:?}", response);
+    let jobs: Vec<output::Job> = jobs.iter().map(output::Job::from).collect();
+    output::list(&jobs)?;
 
     Ok(())
 }
 
+async fn pause_job(matches: &ArgMatches<'_>) -> Result<(), Box<dyn std::error::Error>> {
+    let until = match matches.value_of("until") {
+        Some(until) => chrono::DateTime::parse_from_rfc3339(until)?.timestamp(),
+        None => 0,
+    };
+
+    let mut client = connect().await?;
+    let request = tonic::Request::new(PauseJobRequest {
+        name: matches.value_of("name").unwrap().into(),
+        reason: matches.value_of("reason").unwrap_or("").into(),
+        until,
+    });
+
+    client.pause_job(request).await?;
+
+    output::one(&Outcome::new(matches.value_of("name").unwrap(), "paused"))?;
+
+    Ok(())
+}
+
+async fn resume_job(matches: &ArgMatches<'_>) -> Result<(), Box<dyn std::error::Error>> {
+    let mut client = connect().await?;
+    let request = tonic::Request::new(ResumeJobRequest {
+        name: matches.value_of("name").unwrap().into(),
+    });
+
+    client.resume_job(request).await?;
+
+    output::one(&Outcome::new(matches.value_of("name").unwrap(), "resumed"))?;
+
+    Ok(())
+}
+
+async fn cancel_execution(matches: &ArgMatches<'_>) -> Result<(), Box<dyn std::error::Error>> {
+    let mut client = connect().await?;
+    let request = tonic::Request::new(CancelExecutionRequest {
+        execution_id: matches.value_of("execution_id").unwrap().into(),
+    });
+
+    client.cancel_execution(request).await?;
+
+    output::one(&Outcome::new(
+        matches.value_of("execution_id").unwrap(),
+        "cancelled",
+    ))?;
+
+    Ok(())
+}
+
+async fn tail_execution(matches: &ArgMatches<'_>) -> Result<(), Box<dyn std::error::Error>> {
+    let mut client = connect().await?;
+    let request = tonic::Request::new(TailExecutionRequest {
+        execution_id: matches.value_of("execution").unwrap_or("").into(),
+        job: matches.value_of("job").unwrap_or("").into(),
+        follow: matches.is_present("follow"),
+    });
+
+    let mut lines = client.tail_execution(request).await?.into_inner();
+
+    let mut first = true;
+    while let Some(line) = lines.message().await? {
+        // the log as it is, unless a format was asked for
+        match output::format() {
+            Format::Table => print!("{}", line.line),
+            _ => output::streamed(&output::LogLine { line: line.line }, first)?,
+        }
+        first = false;
+    }
+
+    Ok(())
+}
+
+async fn list_executions(matches: &ArgMatches<'_>) -> Result<(), Box<dyn std::error::Error>> {
+    let mut client = connect().await?;
+    let request = tonic::Request::new(ListExecutionsRequest {
+        job: matches.value_of("job").unwrap().into(),
+        statuses: values(matches, "status")
+            .iter()
+            .map(|status| execution_status(status))
+            .collect::<Result<_, _>>()?,
+        started_after: 0,
+        started_before: 0,
+        page_size: match matches.value_of("limit") {
+            Some(limit) => <i32 as FromStr>::from_str(limit)?,
+            None => 0,
+        },
+        page_token: matches.value_of("page_token").unwrap_or("").into(),
+    });
+
+    let response = client.list_executions(request).await?.into_inner();
+
+    let page = output::ExecutionPage {
+        executions: response
+            .executions
+            .iter()
+            .map(output::Execution::from)
+            .collect(),
+        next_page_token: match response.next_page_token.is_empty() {
+            true => None,
+            false => Some(response.next_page_token),
+        },
+    };
+
+    match output::format() {
+        Format::Table => {
+            output::list(&page.executions)?;
+            // on stderr so the table can be piped
+            if let Some(token) = page.next_page_token {
+                eprintln!("more executions with --page-token {}", token);
+            }
+        }
+        _ => output::serialized(&page)?,
+    }
+
+    Ok(())
+}
+
+async fn get_execution(matches: &ArgMatches<'_>) -> Result<(), Box<dyn std::error::Error>> {
+    let mut client = connect().await?;
+    let request = tonic::Request::new(GetExecutionRequest {
+        id: matches.value_of("id").unwrap().into(),
+    });
+
+    let execution = client.get_execution(request).await?.into_inner();
+
+    output::one(&output::Execution::from(&execution))?;
+
+    Ok(())
+}
+
+async fn get_log_url(matches: &ArgMatches<'_>) -> Result<(), Box<dyn std::error::Error>> {
+    let mut client = connect().await?;
+    let request = tonic::Request::new(GetLogUrlRequest {
+        execution_id: matches.value_of("id").unwrap().into(),
+        ttl: ttl(matches),
+    });
+
+    let url = match client.get_log_url(request).await {
+        Ok(response) => response.into_inner(),
+        Err(status) => {
+            let instead = format!(
+                "dcron-client logs --execution {}",
+                matches.value_of("id").unwrap()
+            );
+            return Err(refused_url(status, &instead).into());
+        }
+    };
+
+    output::one(&output::SignedUrl::from(&url))?;
+
+    Ok(())
+}
+
+async fn get_script_url(matches: &ArgMatches<'_>) -> Result<(), Box<dyn std::error::Error>> {
+    let mut client = connect().await?;
+    let request = tonic::Request::new(GetScriptUrlRequest {
+        job: matches.value_of("name").unwrap().into(),
+        ttl: ttl(matches),
+    });
+
+    let url = match client.get_script_url(request).await {
+        Ok(response) => response.into_inner(),
+        Err(status) => {
+            let instead = format!(
+                "dcron-client get {} --script",
+                matches.value_of("name").unwrap()
+            );
+            return Err(refused_url(status, &instead).into());
+        }
+    };
+
+    output::one(&output::SignedUrl::from(&url))?;
+
+    Ok(())
+}
+
+// The server cannot sign urls of encrypted objects, say how to read them
+fn refused_url(status: tonic::Status, instead: &str) -> tonic::Status {
+    let subject = format!("{}: ", storage::ENCRYPTED_URL);
+    match status.code() == Code::FailedPrecondition && status.message().starts_with(&subject) {
+        true => tonic::Status::failed_precondition(format!(
+            "{} (urls give the stored bytes, which are encrypted), read it with `{}` instead",
+            status.message(),
+            instead
+        )),
+        false => status,
+    }
+}
+
+async fn watch(matches: &ArgMatches<'_>) -> Result<(), Box<dyn std::error::Error>> {
+    let mut client = connect().await?;
+    let request = tonic::Request::new(WatchRequest {
+        job: matches.value_of("job").unwrap_or("").into(),
+        labels: key_values(matches, "label"),
+        cursor: matches.value_of("cursor").unwrap_or("").into(),
+    });
+
+    let mut events = client.watch(request).await?.into_inner();
+
+    let mut first = true;
+    while let Some(event) = events.message().await? {
+        output::streamed(&output::Event::from(&event), first)?;
+        first = false;
+    }
+
+    Ok(())
+}
+
+async fn apply(matches: &ArgMatches<'_>) -> Result<(), Box<dyn std::error::Error>> {
+    let jobs = manifest::load(Path::new(matches.value_of("file").unwrap()))?;
+
+    let mut client = connect().await?;
+    let changes = plan(&mut client, jobs, matches.is_present("prune")).await?;
+
+    // printed as they are done, so a failure does not hide what was applied
+    for (i, change) in changes.into_iter().enumerate() {
+        let outcome = match change {
+            Change::Create(job) => {
+                let script = upload_file(&job.script).await?;
+                client.new_job(job.to_request(&script)?).await?;
+                Outcome::new(&job.name, "created")
+            }
+            Change::Update {
+                job,
+                script,
+                fields,
+            } => {
+                let paths: Vec<&str> = fields.iter().map(|diff| diff.field).collect();
+                // only upload the script when its content changed
+                let script = if paths.contains(&"location") {
+                    upload_file(&job.script).await?
+                } else {
+                    script
+                };
+
+                client
+                    .update_job(UpdateJobRequest {
+                        name: job.name.clone(),
+                        job: Some(job.to_request(&script)?),
+                        update_mask: Some(prost_types::FieldMask {
+                            paths: paths.iter().map(|path| path.to_string()).collect(),
+                        }),
+                    })
+                    .await?;
+                Outcome {
+                    name: job.name,
+                    result: "updated",
+                    changes: fields.into_iter().map(output_change).collect(),
+                }
+            }
+            Change::Unchanged(name) => Outcome::new(&name, "unchanged"),
+            Change::Disable(name) => {
+                client
+                    .disable_job(DisableJobRequest { name: name.clone() })
+                    .await?;
+                Outcome::new(&name, "disabled")
+            }
+        };
+        output::streamed(&outcome, i == 0)?;
+    }
+
+    Ok(())
+}
+
+async fn diff(matches: &ArgMatches<'_>) -> Result<(), Box<dyn std::error::Error>> {
+    let jobs = manifest::load(Path::new(matches.value_of("file").unwrap()))?;
+
+    let mut client = connect().await?;
+    let changes = plan(&mut client, jobs, matches.is_present("prune")).await?;
+
+    let outcomes: Vec<Outcome> = changes
+        .into_iter()
+        .map(|change| match change {
+            Change::Create(job) => Outcome::new(&job.name, "create"),
+            Change::Update { job, fields, .. } => Outcome {
+                name: job.name,
+                result: "update",
+                changes: fields.into_iter().map(output_change).collect(),
+            },
+            Change::Unchanged(name) => Outcome::new(&name, "unchanged"),
+            Change::Disable(name) => Outcome::new(&name, "disable"),
+        })
+        .collect();
+
+    match output::format() {
+        Format::Table => {
+            for outcome in &outcomes {
+                match outcome.result {
+                    "create" => println!("+ {} (create)", outcome.name),
+                    "update" => println!("~ {} (update)", outcome.name),
+                    "disable" => println!("- {} (disable)", outcome.name),
+                    _ => continue,
+                }
+                for change in &outcome.changes {
+                    println!(
+                        "    {}: {} -> {}",
+                        change.field, change.current, change.wanted
+                    );
+                }
+            }
+        }
+        _ => output::list(&outcomes)?,
+    }
+
+    // so CI can fail when the server is not in sync with the manifests
+    if outcomes.iter().any(|outcome| outcome.result != "unchanged") {
+        return Err(Differences.into());
+    }
+
+    Ok(())
+}
+
+fn output_change(diff: manifest::FieldDiff) -> output::Change {
+    output::Change {
+        field: diff.field.into(),
+        current: diff.current,
+        wanted: diff.wanted,
+    }
+}
+
+// Compares the manifests with the jobs on the server, without changing anything
+async fn plan(
+    client: &mut PublicClient<Channel>,
+    jobs: Vec<manifest::JobSpec>,
+    prune: bool,
+) -> Result<Vec<Change>, Box<dyn std::error::Error>> {
+    let names: HashSet<String> = jobs.iter().map(|job| job.name.clone()).collect();
+    let mut changes = vec![];
+
+    for job in jobs {
+        let current = match client
+            .get_job(JobStatusRequest {
+                name: job.name.clone(),
+            })
+            .await
+        {
+            Ok(response) => response.into_inner(),
+            Err(status) if status.code() == Code::NotFound => {
+                changes.push(Change::Create(job));
+                continue;
+            }
+            Err(status) => return Err(status.into()),
+        };
+
+        let script = StoredScript {
+            location: current.location.clone(),
+            digest: current.script_digest.clone(),
+        };
+        let mut fields = manifest::diff(&job.to_request(&script)?, &current);
+
+        // scripts are compared by digest, jobs created before scripts had
+        // one get theirs when apply uploads the script again
+        let local = storage::digest(&script_content(&job.script)?);
+        let remote = match current.script_digest.is_empty() {
+            false => current.script_digest.clone(),
+            true => "unknown".into(),
+        };
+        if local != remote {
+            fields.push(manifest::FieldDiff {
+                field: "location",
+                current: remote,
+                wanted: local,
+            });
+        }
+
+        if fields.is_empty() {
+            changes.push(Change::Unchanged(job.name));
+        } else {
+            changes.push(Change::Update {
+                job,
+                script,
+                fields,
+            });
+        }
+    }
+
+    if prune {
+        let active = client.list_jobs(ListJobsRequest {}).await?.into_inner().jobs;
+
+        for job in active {
+            let managed = job.labels.get(manifest::MANAGED_BY_LABEL).map(String::as_str)
+                == Some(manifest::MANAGED_BY);
+            if managed && !names.contains(&job.name) {
+                changes.push(Change::Disable(job.name));
+            }
+        }
+    }
+
+    Ok(changes)
+}
+
+async fn stored_script(location: &str) -> Result<Option<Vec<u8>>, Box<dyn std::error::Error>> {
+    let minio_config = match CONFIG.get().and_then(|config| config.minio.as_ref()) {
+        Some(minio_config) => minio_config,
+        None => return Err(storage::Error::internal("No configuration for minio").into()),
+    };
+
+    Ok(storage::Client::connect(minio_config, storage::Objects::Scripts)?
+        .get(location)
+        .await?)
+}
+
+async fn import_crontab(matches: &ArgMatches<'_>) -> Result<(), Box<dyn std::error::Error>> {
+    let file = Path::new(matches.value_of("file").unwrap());
+    let entries = crontab::parse(&fs::read_to_string(file)?)?;
+
+    let prefix = match matches.value_of("prefix") {
+        Some(prefix) => prefix.to_string(),
+        None => match file.file_stem() {
+            Some(stem) => stem.to_string_lossy().into(),
+            None => return Err(format!("{} is not a file", file.display()).into()),
+        },
+    };
+    let timeout = match matches.value_of("timeout") {
+        Some(timeout) => <i32 as FromStr>::from_str(timeout)?,
+        None => 0,
+    };
+    let source = match file.file_name() {
+        Some(name) => name.to_string_lossy().to_string(),
+        None => return Err(format!("{} is not a file", file.display()).into()),
+    };
+    let options = JobRequest {
+        retention: retention(matches),
+        timeout,
+        job_type: ScriptType::Shell as i32,
+        args: values(matches, "arg"),
+        env: key_values(matches, "env"),
+        labels: key_values(matches, "label"),
+        ..Default::default()
+    };
+
+    let dry_run = matches.is_present("dry_run");
+    let mut client = match dry_run {
+        true => None,
+        false => Some(connect().await?),
+    };
+
+    for (i, entry) in entries.into_iter().enumerate() {
+        let name = format!("{}-{}", prefix, entry.id());
+        let mut job = entry.to_request(&name, &options, &source);
+
+        let client = match client.as_mut() {
+            Some(client) => client,
+            None => {
+                let mut outcome = Outcome::new(&name, "create");
+                let mut env: Vec<String> = job
+                    .env
+                    .iter()
+                    .map(|(key, value)| format!("{}={}", key, value))
+                    .collect();
+                env.sort();
+                for (field, wanted) in [
+                    ("time", entry.schedule),
+                    ("command", entry.command),
+                    ("env", env.join(",")),
+                ] {
+                    outcome.changes.push(output::Change {
+                        field: field.into(),
+                        current: "".into(),
+                        wanted,
+                    });
+                }
+                output::streamed(&outcome, i == 0)?;
+                continue;
+            }
+        };
+
+        // jobs are named after their content, so importing the file again
+        // leaves the jobs it already created as they are
+        let current = match client
+            .get_job(JobStatusRequest { name: name.clone() })
+            .await
+        {
+            Ok(response) => Some(response.into_inner()),
+            Err(status) if status.code() == Code::NotFound => None,
+            Err(status) => return Err(status.into()),
+        };
+        let fields = match &current {
+            Some(current) => {
+                let mut fields = manifest::diff(&job, current);
+                if current.script_digest != job.script_digest {
+                    fields.push(manifest::FieldDiff {
+                        field: "location",
+                        current: current.script_digest.clone(),
+                        wanted: job.script_digest.clone(),
+                    });
+                }
+                if fields.is_empty() {
+                    output::streamed(&Outcome::new(&name, "unchanged"), i == 0)?;
+                    continue;
+                }
+                fields
+            }
+            None => vec![],
+        };
+
+        // removed when dropped
+        let mut script = tempfile::Builder::new()
+            .prefix(&name)
+            .suffix(".sh")
+            .tempfile()?;
+        write!(script, "{}", entry.script(&source))?;
+        let stored = upload_file(script.path()).await?;
+
+        job.location = stored.location;
+        job.script_digest = stored.digest;
+        // an existing job is replaced with the options of this import
+        job.update_if_exists = current.is_some();
+        client.new_job(job).await?;
+
+        let outcome = match current {
+            Some(_) => Outcome {
+                name,
+                result: "updated",
+                changes: fields.into_iter().map(output_change).collect(),
+            },
+            None => Outcome::new(&name, "created"),
+        };
+        output::streamed(&outcome, i == 0)?;
+    }
+
+    Ok(())
+}
+
+fn execution_status(status: &str) -> Result<i32, Box<dyn std::error::Error>> {
+    let status = match status {
+        "running" => ExecutionStatus::Running,
+        "timeout" => ExecutionStatus::Timeout,
+        "failed" => ExecutionStatus::Failed,
+        "succeeded" => ExecutionStatus::Succeeded,
+        "skipped" => ExecutionStatus::Skipped,
+        "cancelled" => ExecutionStatus::Cancelled,
+        _ => return Err(format!("execution status {} not supported", status).into()),
+    };
+    Ok(status as i32)
+}
+
 async fn disable_job(matches: &ArgMatches<'_>) -> Result<(), Box<dyn std::error::Error>> {
-    let client = PublicClient::connect("http://[::1]:50051");
+    let mut client = connect().await?;
     let request = tonic::Request::new(DisableJobRequest {
         name: matches.value_of("name").unwrap().into(),
     });
 
-    let response = client.await.unwrap().disable_job(request).await.unwrap();
+    client.disable_job(request).await?;
 
-    println!("RESPONSE={:?}", response);
+    output::one(&Outcome::new(matches.value_of("name").unwrap(), "disabled"))?;
 
     Ok(())
 }
@@ -152,50 +1288,280 @@ async fn disable_job(matches: &ArgMatches<'_>) -> Result<(), Box<dyn std::error:
 async fn create_job(matches: &ArgMatches<'_>) -> Result<(), Box<dyn std::error::Error>> {
     let file = Path::new(matches.value_of("script").unwrap());
 
-    let client = PublicClient::connect("http://[::1]:50051"); //TODO: change with ENV variables
+    let mut client = connect().await?;
 
-    let file = upload_file(file).await.unwrap();
+    let entrypoint = entrypoint(file, matches.value_of("entrypoint"))?;
+    let script = upload_file(file).await?;
 
     let request = tonic::Request::new(JobRequest {
         name: matches.value_of("name").unwrap().into(),
         time: matches.value_of("time").unwrap().into(),
-        location: file,
-        timeout: <i32 as FromStr>::from_str(matches.value_of("timeout").unwrap()).unwrap(),
+        location: script.location,
+        script_digest: script.digest,
+        entrypoint,
+        retention: retention(matches),
+        timeout: <i32 as FromStr>::from_str(matches.value_of("timeout").unwrap())?,
         update_if_exists: matches.is_present("update_if_exists"),
-        job_type: job_type(matches.value_of("type").unwrap()),
+        job_type: manifest::script_type(matches.value_of("type").unwrap())? as i32,
+        args: values(matches, "arg"),
+        env: key_values(matches, "env"),
+        labels: key_values(matches, "label"),
+    });
+
+    let response = client.new_job(request).await?.into_inner();
+
+    output::one(&Outcome::new(&response.name, "created"))?;
+
+    Ok(())
+}
+
+async fn update_job(matches: &ArgMatches<'_>) -> Result<(), Box<dyn std::error::Error>> {
+    let mut client = connect().await?;
+
+    let mut job = JobRequest::default();
+    let mut paths = vec![];
+
+    if let Some(time) = matches.value_of("time") {
+        job.time = time.into();
+        paths.push("time");
+    }
+    if let Some(timeout) = matches.value_of("timeout") {
+        job.timeout = <i32 as FromStr>::from_str(timeout)?;
+        paths.push("timeout");
+    }
+    if let Some(user_type) = matches.value_of("type") {
+        job.job_type = manifest::script_type(user_type)? as i32;
+        paths.push("job_type");
+    }
+    if let Some(script) = matches.value_of("script") {
+        let path = Path::new(script);
+        job.entrypoint = entrypoint(path, matches.value_of("entrypoint"))?;
+        let script = upload_file(path).await?;
+        job.location = script.location;
+        job.script_digest = script.digest;
+        paths.push("location");
+    }
+    if matches.is_present("arg") {
+        job.args = values(matches, "arg");
+        paths.push("args");
+    }
+    if matches.is_present("env") {
+        job.env = key_values(matches, "env");
+        paths.push("env");
+    }
+    if matches.is_present("label") {
+        job.labels = key_values(matches, "label");
+        paths.push("labels");
+    }
+    // both fields are replaced, the one not given falls back to the config
+    if let Some(retention) = retention(matches) {
+        job.retention = Some(retention);
+        paths.push("retention");
+    }
+
+    let request = tonic::Request::new(UpdateJobRequest {
+        name: matches.value_of("name").unwrap().into(),
+        job: Some(job),
+        update_mask: Some(prost_types::FieldMask {
+            paths: paths.into_iter().map(String::from).collect(),
+        }),
     });
 
-    let response = client.await.unwrap().new_job(request).await.unwrap();
+    let response = client.update_job(request).await?.into_inner();
 
-    println!("RESPONSE={:?}", response);
+    output::one(&Outcome::new(&response.name, "updated"))?;
 
     Ok(())
 }
 
-fn job_type(user_type: &str) -> i32 {
-    match user_type {
-        "python" => ScriptType::Python as i32,
-        "ruby" => ScriptType::Ruby as i32,
-        _ => panic!("Script type not supported"),
+fn entrypoint_option<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("entrypoint")
+        .long("entrypoint")
+        .value_name("PATH")
+        .help("Script to run inside the bundle, when the script is a directory or a tar.gz/zip file")
+        .takes_value(true)
+}
+
+// Options shared by create and update
+fn job_options<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("arg")
+            .long("arg")
+            .value_name("ARG")
+            .help("Argument passed to the script, can be repeated")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1),
+        Arg::with_name("env")
+            .long("env")
+            .value_name("KEY=VALUE")
+            .help("Environment variable set for the script, can be repeated")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .validator(is_key_value),
+        Arg::with_name("label")
+            .long("label")
+            .value_name("KEY=VALUE")
+            .help("Label attached to the job, can be repeated")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .validator(is_key_value),
+        Arg::with_name("keep_executions")
+            .long("keep-executions")
+            .value_name("COUNT")
+            .help("Executions kept with their logs, the server config decides when not set")
+            .takes_value(true)
+            .validator(is_number),
+        Arg::with_name("keep_days")
+            .long("keep-days")
+            .value_name("DAYS")
+            .help("Days executions are kept with their logs, the server config decides when not set")
+            .takes_value(true)
+            .validator(is_number),
+    ]
+}
+
+fn is_number(value: String) -> Result<(), String> {
+    match value.parse::<u32>() {
+        Ok(_) => Ok(()),
+        Err(_) => Err(format!("{} is not a positive number", value)),
     }
 }
 
-async fn upload_file(path: &Path) -> Result<String, storage::Error> {
-    let config = match CONFIG.get() {
-        Some(config) => config,
-        //TODO do not do this
-        _ => return Err(storage::Error{message: "Could not get a config object".into()}),
+// None when neither --keep-executions nor --keep-days is given
+fn retention(matches: &ArgMatches<'_>) -> Option<Retention> {
+    if !matches.is_present("keep_executions") && !matches.is_present("keep_days") {
+        return None;
+    }
+
+    let number = |name| {
+        matches
+            .value_of(name)
+            .and_then(|value| value.parse().ok())
+            .unwrap_or_default()
     };
+    Some(Retention {
+        keep_executions: number("keep_executions"),
+        keep_days: number("keep_days"),
+    })
+}
 
-    let minio_config = match &config.minio {
-        Some(minio_config) => minio_config,
-        _ => panic!("No configuration for minio"),
+fn ttl_option<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("ttl")
+        .long("ttl")
+        .value_name("SECONDS")
+        .help("How long the url works, 15 minutes by default and 7 days at most")
+        .takes_value(true)
+        .validator(is_number)
+}
+
+// 0 lets the server pick its default
+fn ttl(matches: &ArgMatches<'_>) -> u32 {
+    matches
+        .value_of("ttl")
+        .and_then(|value| value.parse().ok())
+        .unwrap_or_default()
+}
+
+fn is_key_value(value: String) -> Result<(), String> {
+    match value.split_once('=') {
+        Some((key, _)) if !key.is_empty() => Ok(()),
+        _ => Err(format!("{} is not in the KEY=VALUE format", value)),
+    }
+}
+
+fn values(matches: &ArgMatches<'_>, name: &str) -> Vec<String> {
+    match matches.values_of(name) {
+        Some(values) => values.map(String::from).collect(),
+        None => vec![],
+    }
+}
+
+fn key_values(matches: &ArgMatches<'_>, name: &str) -> HashMap<String, String> {
+    values(matches, name)
+        .iter()
+        .filter_map(|value| value.split_once('='))
+        .map(|(key, value)| (key.into(), value.into()))
+        .collect()
+}
+
+// The entrypoint of the job whose script is at `path`, empty for single
+// scripts. Directories are packed as a bundle so they need one.
+fn entrypoint(path: &Path, entrypoint: Option<&str>) -> Result<String, Box<dyn std::error::Error>> {
+    let entrypoint = match (path.is_dir(), entrypoint) {
+        (_, Some(entrypoint)) => entrypoint,
+        (true, None) => return Err(format!("{} is a directory, set --entrypoint", path.display()).into()),
+        (false, None) => return Ok("".into()),
     };
 
-    storage::Client::connect(&minio_config)
-        .put(
-            path.to_str().unwrap(),
-            path.file_name().unwrap().to_str().unwrap(),
-        )
-        .await
+    if !bundle::is_entrypoint(entrypoint) {
+        return Err(format!("entrypoint {} must be a relative path", entrypoint).into());
+    }
+    if path.is_dir() && !path.join(entrypoint).is_file() {
+        return Err(format!("{} has no file {}", path.display(), entrypoint).into());
+    }
+
+    Ok(entrypoint.into())
+}
+
+// The file, or the directory packed as a bundle
+fn script_content(path: &Path) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    if path.is_dir() {
+        return match bundle::pack(path) {
+            Ok(content) => Ok(content),
+            Err(error) => Err(format!("could not pack {}: {}", path.display(), error).into()),
+        };
+    }
+
+    match fs::read(path) {
+        Ok(content) => Ok(content),
+        Err(error) => Err(format!("could not read {}: {}", path.display(), error).into()),
+    }
+}
+
+// Uploads straight to the object storage when the config has its
+// credentials, otherwise through the server
+async fn upload_file(path: &Path) -> Result<StoredScript, Box<dyn std::error::Error>> {
+    let name = match path.file_name() {
+        Some(name) if path.is_dir() => format!("{}.tar.gz", name.to_string_lossy()),
+        Some(name) => name.to_string_lossy().to_string(),
+        None => return Err(format!("{} is not a file", path.display()).into()),
+    };
+
+    let content = script_content(path)?;
+
+    if let Some(minio_config) = CONFIG.get().and_then(|config| config.minio.as_ref()) {
+        let script = storage::Client::connect(minio_config, storage::Objects::Scripts)?
+            .put_script(&content)
+            .await?;
+        return Ok(script);
+    }
+
+    // an empty script still needs one chunk to carry the name
+    let mut chunks = vec![];
+    for (i, content) in content.chunks(UPLOAD_CHUNK_SIZE).enumerate() {
+        chunks.push(ScriptChunk {
+            name: if i == 0 { name.clone() } else { "".into() },
+            content: content.to_vec(),
+        });
+    }
+    if chunks.is_empty() {
+        chunks.push(ScriptChunk {
+            name,
+            content: vec![],
+        });
+    }
+
+    let mut client = connect().await?;
+    let response = client
+        .upload_script(futures::stream::iter(chunks))
+        .await?
+        .into_inner();
+
+    Ok(StoredScript {
+        location: response.location,
+        digest: response.digest,
+    })
 }
@@ -1,10 +1,14 @@
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use std::fs;
 use std::fmt;
-#[derive(Deserialize, Clone, Debug)]
+#[derive(Deserialize, Clone, Debug, Default)]
 pub struct Config {
     pub database: Option<Database>,
     pub minio: Option<Minio>,
+    pub scheduler: Option<Scheduler>,
+    pub worker: Option<Worker>,
+    pub client: Option<Client>,
+    pub retention: Option<Retention>,
 }
 
 #[derive(Deserialize, Clone)]
@@ -16,16 +20,144 @@ pub struct Database {
 
 #[derive(Deserialize, Clone)]
 pub struct Minio {
+    // where the objects are kept, s3 by default
+    #[serde(default)]
+    pub backend: Backend,
+    // credentials and url of the s3 backend
+    #[serde(default)]
     pub username: String,
+    #[serde(default)]
     pub password: String,
+    #[serde(default)]
     pub host: String,
+    // directory of the local backend, with a subdirectory per bucket
+    pub path: Option<String>,
+    // the bucket of scripts and logs, defaults to rust-s3
+    pub bucket: Option<String>,
+    // override `bucket` for one kind of object
+    pub scripts_bucket: Option<String>,
+    pub logs_bucket: Option<String>,
+    // e.g. us-east-1, minio does not need it
+    pub region: Option<String>,
+    // prepended to every object name, e.g. "dcron/"
+    pub prefix: Option<String>,
+    // host/bucket urls, false for bucket.host urls, defaults to true
+    pub path_style: Option<bool>,
+    // the server creates the missing buckets when it starts
+    #[serde(default)]
+    pub create_buckets: bool,
+    // scripts and logs are stored in plaintext without it
+    pub encryption: Option<Encryption>,
+}
+
+// AES-256-GCM keys of the objects. Every node and client reading or writing
+// the objects needs the same keys.
+#[derive(Deserialize, Clone)]
+pub struct Encryption {
+    // id of the key new objects are encrypted with, stored with each object
+    pub key_id: String,
+    // hex encoded 32 bytes, or a file with them
+    pub key: Option<String>,
+    pub key_file: Option<String>,
+    // keys objects written before a rotation are still encrypted with
+    #[serde(default)]
+    pub old_keys: Vec<Key>,
+    // read objects without a key id as plaintext, for the objects stored
+    // before encryption was enabled
+    #[serde(default)]
+    pub allow_plaintext: bool,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct Key {
+    pub id: String,
+    pub key: Option<String>,
+    pub key_file: Option<String>,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    // Minio, AWS, Ceph or any other storage with the S3 api
+    #[default]
+    S3,
+    // a directory, for a single box
+    Local,
+    // lost when the process ends, the server and its worker share it
+    Memory,
+}
+
+// How a log is compressed in the storage, also recorded with the execution
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    // stored as written, the logs of executions from before compression
+    #[default]
+    None,
+    Gzip,
+    Zstd,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct Scheduler {
+    // Internal service urls of the workers, jobs are sent round-robin
+    pub workers: Vec<String>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct Worker {
+    // Url the other nodes use to reach this worker
+    pub url: String,
+    // Scripts and logs are kept under it while the job runs
+    pub work_dir: Option<String>,
+    // Downloaded scripts are kept here between executions, defaults to
+    // the cache directory inside work_dir
+    pub script_cache_dir: Option<String>,
+    // MiB, 0 disables the cache, 512 by default
+    pub script_cache_size: Option<u64>,
+    // gzip by default
+    pub log_compression: Option<Compression>,
+    // MiB kept of the log of an execution, the start and the end of longer
+    // logs are kept. 0 keeps everything, 64 by default
+    pub log_max_size: Option<u64>,
+}
+
+// Default retention of the executions and their logs, jobs can override
+// each field. Nothing is deleted without it.
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct Retention {
+    // the most recent executions kept per job
+    pub keep_executions: Option<u32>,
+    // executions that started longer ago are deleted
+    pub keep_days: Option<u32>,
+    // seconds between two runs of the janitor, 3600 by default
+    pub interval: Option<u64>,
+    // only print what would be deleted
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+// How dcron-client reaches the server, the command line options win
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct Client {
+    // e.g. https://dcron.example.com:50051
+    pub server: Option<String>,
+    // PEM files, the CA is needed for servers with a private CA
+    pub ca_cert: Option<String>,
+    pub client_cert: Option<String>,
+    pub client_key: Option<String>,
+    // name checked against the server certificate, defaults to the host
+    pub domain: Option<String>,
+    // seconds
+    pub connect_timeout: Option<u64>,
+    pub timeout: Option<u64>,
 }
 
 impl fmt::Debug for Database {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("Database")
          .field("username", &self.username)
-         .field("password", &format!("*****"))
+         .field("password", &"*****")
          .field("cluster_url", &self.cluster_url)
          .finish()
     }
@@ -34,16 +166,49 @@ impl fmt::Debug for Database {
 impl fmt::Debug for Minio {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("Minio")
+         .field("backend", &self.backend)
          .field("username", &self.username)
-         .field("password", &format!("*****"))
+         .field("password", &"*****")
          .field("host", &self.host)
+         .field("path", &self.path)
+         .field("bucket", &self.bucket)
+         .field("scripts_bucket", &self.scripts_bucket)
+         .field("logs_bucket", &self.logs_bucket)
+         .field("region", &self.region)
+         .field("prefix", &self.prefix)
+         .field("path_style", &self.path_style)
+         .field("create_buckets", &self.create_buckets)
+         .field("encryption", &self.encryption)
+         .finish()
+    }
+}
+
+impl fmt::Debug for Encryption {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Encryption")
+         .field("key_id", &self.key_id)
+         .field("key", &self.key.as_ref().map(|_| "*****"))
+         .field("key_file", &self.key_file)
+         .field("old_keys", &self.old_keys)
+         .field("allow_plaintext", &self.allow_plaintext)
+         .finish()
+    }
+}
+
+impl fmt::Debug for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Key")
+         .field("id", &self.id)
+         .field("key", &self.key.as_ref().map(|_| "*****"))
+         .field("key_file", &self.key_file)
          .finish()
     }
 }
 
 impl Config {
     pub fn from(file: &str) -> Result<Config, Box<dyn std::error::Error>> {
-        let config: String = fs::read_to_string(file).unwrap();
-        Ok(toml::from_str(&config).unwrap())
+        let config: String = fs::read_to_string(file)
+            .map_err(|e| format!("could not read {}: {}", file, e))?;
+        Ok(toml::from_str(&config).map_err(|e| format!("invalid config {}: {}", file, e))?)
     }
 }
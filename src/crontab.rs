@@ -0,0 +1,435 @@
+use crate::dcron::JobRequest;
+use crate::storage;
+use anyhow::anyhow;
+use job_scheduler::Schedule;
+use std::collections::{BTreeSet, HashMap};
+use std::str::FromStr;
+
+// A command of a crontab file with the schedule in the format dcron uses
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub line: usize,
+    pub schedule: String,
+    pub command: String,
+    // the variables assigned above the command
+    pub env: HashMap<String, String>,
+}
+
+impl Entry {
+    // Taken from the content rather than the line, so adding a line does not
+    // rename the jobs of the lines below it
+    pub fn id(&self) -> String {
+        storage::digest(format!("{}\n{}", self.schedule, self.command).as_bytes())[..8].into()
+    }
+
+    // The script of the job, without the line number for the same reason
+    pub fn script(&self, source: &str) -> String {
+        format!("# imported from {}\n{}\n", source, self.command)
+    }
+
+    // `job` has the options shared by every job of the file, the
+    // variables of the crontab win over its env
+    pub fn to_request(&self, name: &str, job: &JobRequest, source: &str) -> JobRequest {
+        let mut env = job.env.clone();
+        env.extend(self.env.clone());
+
+        JobRequest {
+            name: name.into(),
+            time: self.schedule.clone(),
+            script_digest: storage::digest(self.script(source).as_bytes()),
+            env,
+            ..job.clone()
+        }
+    }
+}
+
+const DAY_NAMES: [&str; 7] = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+// Parses a user crontab (no user column). Fails on the first line dcron
+// cannot run the same way, so nothing is imported half-way.
+pub fn parse(content: &str) -> Result<Vec<Entry>, anyhow::Error> {
+    let mut entries: Vec<Entry> = vec![];
+    let mut env = HashMap::new();
+
+    for (i, line) in content.lines().enumerate() {
+        let number = i + 1;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        if let Some((name, value)) = assignment(line) {
+            // the worker runs shell jobs with sh, whatever the crontab says
+            if name == "SHELL" {
+                if value != "/bin/sh" {
+                    return Err(anyhow!(
+                        "line {}: SHELL={} is not supported, imported jobs run with /bin/sh",
+                        number,
+                        value
+                    ));
+                }
+                continue;
+            }
+            env.insert(name, value);
+            continue;
+        }
+
+        let (schedule, command) = match line.strip_prefix('@') {
+            Some(line) => {
+                let (name, command) = split_fields(line, 1)
+                    .ok_or_else(|| anyhow!("line {}: missing command", number))?;
+                (macro_schedule(&name[0], number)?, command)
+            }
+            None => {
+                let (fields, command) = split_fields(line, 5)
+                    .ok_or_else(|| anyhow!("line {}: expected 5 fields and a command", number))?;
+                (schedule(&fields, number)?, command)
+            }
+        };
+
+        if let Err(e) = Schedule::from_str(&schedule) {
+            return Err(anyhow!(
+                "line {}: invalid schedule {}: {}",
+                number,
+                schedule,
+                e
+            ));
+        }
+
+        let entry = Entry {
+            line: number,
+            schedule,
+            command: command_text(&command, number)?,
+            env: env.clone(),
+        };
+        if let Some(other) = entries.iter().find(|other| other.id() == entry.id()) {
+            return Err(anyhow!(
+                "line {}: same schedule and command as line {}",
+                number,
+                other.line
+            ));
+        }
+        entries.push(entry);
+    }
+
+    Ok(entries)
+}
+
+// `NAME=value` or `NAME = "value"`
+fn assignment(line: &str) -> Option<(String, String)> {
+    let (name, value) = line.split_once('=')?;
+    let name = name.trim();
+    let valid_name = !name.is_empty()
+        && !name.starts_with(|c: char| c.is_ascii_digit())
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !valid_name {
+        return None;
+    }
+
+    let value = value.trim();
+    let unquoted = ['"', '\'']
+        .iter()
+        .find_map(|quote| value.strip_prefix(*quote)?.strip_suffix(*quote));
+
+    Some((name.into(), unquoted.unwrap_or(value).into()))
+}
+
+// Splits the first `count` whitespace separated fields from the rest of the line
+fn split_fields(line: &str, count: usize) -> Option<(Vec<String>, String)> {
+    let mut fields = vec![];
+    let mut rest = line;
+
+    for _ in 0..count {
+        rest = rest.trim_start();
+        let end = rest.find(char::is_whitespace)?;
+        fields.push(rest[..end].to_string());
+        rest = &rest[end..];
+    }
+
+    let command = rest.trim();
+    if command.is_empty() {
+        return None;
+    }
+    Some((fields, command.into()))
+}
+
+fn macro_schedule(name: &str, number: usize) -> Result<String, anyhow::Error> {
+    let schedule = match name {
+        "yearly" | "annually" => "0 0 0 1 1 *",
+        "monthly" => "0 0 0 1 * *",
+        "weekly" => "0 0 0 * * Sun",
+        "daily" | "midnight" => "0 0 0 * * *",
+        "hourly" => "0 0 * * * *",
+        "reboot" => return Err(anyhow!("line {}: @reboot is not supported", number)),
+        _ => return Err(anyhow!("line {}: unknown macro @{}", number, name)),
+    };
+    Ok(schedule.into())
+}
+
+// dcron schedules have seconds and only support steps as `start/step`
+fn schedule(fields: &[String], number: usize) -> Result<String, anyhow::Error> {
+    // cron runs when either day matches, dcron only when both do
+    if fields[2] != "*" && fields[4] != "*" {
+        return Err(anyhow!(
+            "line {}: restrict the day of the month or the day of the week, not both",
+            number
+        ));
+    }
+
+    Ok(format!(
+        "0 {} {} {} {} {}",
+        steps(&fields[0], 0, 59, number)?,
+        steps(&fields[1], 0, 23, number)?,
+        steps(&fields[2], 1, 31, number)?,
+        steps(&fields[3], 1, 12, number)?,
+        days_of_week(&fields[4], number)?
+    ))
+}
+
+// `*/step` becomes `min/step` and ranges with steps become lists
+fn steps(field: &str, min: u32, max: u32, number: usize) -> Result<String, anyhow::Error> {
+    let mut parts = vec![];
+
+    for part in field.split(',') {
+        let part = match part.split_once('/') {
+            None => part.to_string(),
+            Some(("*", step)) => format!("{}/{}", min, step_size(step, number)?),
+            Some((range, step)) => {
+                let (start, end) = bounds(range, max, number)?;
+                let values: Vec<String> = (start..=end)
+                    .step_by(step_size(step, number)?)
+                    .map(|value| value.to_string())
+                    .collect();
+                values.join(",")
+            }
+        };
+        parts.push(part);
+    }
+
+    Ok(parts.join(","))
+}
+
+// dcron counts the days of the week from 1 (Sunday), crontab from 0 and
+// also accepts 7 for Sunday, so days are mapped one by one
+fn days_of_week(field: &str, number: usize) -> Result<String, anyhow::Error> {
+    if field == "*" {
+        return Ok(field.into());
+    }
+
+    let mut days = BTreeSet::new();
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step_size(step, number)?),
+            None => (part, 1),
+        };
+        let (start, end) = match range.split_once('-') {
+            _ if range == "*" => (0, 6),
+            Some((start, end)) => (day(start, number)?, day(end, number)?),
+            None if part.contains('/') => (day(range, number)?, 7),
+            None => (day(range, number)?, day(range, number)?),
+        };
+        if start > end {
+            return Err(anyhow!("line {}: invalid range {}", number, range));
+        }
+
+        for day in (start..=end).step_by(step) {
+            days.insert(day % 7 + 1);
+        }
+    }
+
+    let days: Vec<String> = days.iter().map(|day| day.to_string()).collect();
+    Ok(days.join(","))
+}
+
+// A day as crontab counts them, from a number or a name such as `Mon`
+fn day(value: &str, number: usize) -> Result<u32, anyhow::Error> {
+    if let Some(day) = DAY_NAMES
+        .iter()
+        .position(|name| name.eq_ignore_ascii_case(value))
+    {
+        return Ok(day as u32);
+    }
+
+    match value.parse::<u32>() {
+        Ok(day) if day <= 7 => Ok(day),
+        _ => Err(anyhow!(
+            "line {}: invalid day of the week {}",
+            number,
+            value
+        )),
+    }
+}
+
+// `start-end`, or `start` which goes until `max` when it has a step
+fn bounds(range: &str, max: u32, number: usize) -> Result<(u32, u32), anyhow::Error> {
+    let parse = |value: &str| {
+        value
+            .parse::<u32>()
+            .map_err(|_| anyhow!("line {}: invalid value {}", number, value))
+    };
+
+    match range.split_once('-') {
+        Some((start, end)) => Ok((parse(start)?, parse(end)?)),
+        None => Ok((parse(range)?, max)),
+    }
+}
+
+fn step_size(step: &str, number: usize) -> Result<usize, anyhow::Error> {
+    match step.parse::<usize>() {
+        Ok(step) if step > 0 => Ok(step),
+        _ => Err(anyhow!("line {}: invalid step {}", number, step)),
+    }
+}
+
+// `%` sends the rest of the line to the command stdin, which the scripts
+// we generate do not support
+fn command_text(command: &str, number: usize) -> Result<String, anyhow::Error> {
+    let unescaped = command.replace("\\%", "");
+    if unescaped.contains('%') {
+        return Err(anyhow!(
+            "line {}: % in commands is not supported, escape it as \\%",
+            number
+        ));
+    }
+    Ok(command.replace("\\%", "%"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dcron::JobStatusResponse;
+    use crate::manifest;
+
+    #[test]
+    fn steps_become_lists() {
+        assert_eq!(steps("*", 0, 59, 1).unwrap(), "*");
+        assert_eq!(steps("*/15", 0, 59, 1).unwrap(), "0/15");
+        assert_eq!(steps("*/2", 1, 31, 1).unwrap(), "1/2");
+        assert_eq!(steps("1-10/3", 0, 59, 1).unwrap(), "1,4,7,10");
+        assert_eq!(steps("50/5", 0, 59, 1).unwrap(), "50,55");
+        assert_eq!(steps("1,5,9", 0, 23, 1).unwrap(), "1,5,9");
+        assert!(steps("*/0", 0, 59, 1).is_err());
+        assert!(steps("a-b/2", 0, 59, 1).is_err());
+    }
+
+    #[test]
+    fn days_of_week_are_counted_from_sunday() {
+        assert_eq!(days_of_week("*", 1).unwrap(), "*");
+        assert_eq!(days_of_week("0", 1).unwrap(), "1");
+        assert_eq!(days_of_week("7", 1).unwrap(), "1");
+        assert_eq!(days_of_week("1-5", 1).unwrap(), "2,3,4,5,6");
+        assert_eq!(days_of_week("*/2", 1).unwrap(), "1,3,5,7");
+        assert_eq!(days_of_week("5/1", 1).unwrap(), "1,6,7");
+    }
+
+    #[test]
+    fn days_of_week_map_names() {
+        assert_eq!(days_of_week("Mon,3", 1).unwrap(), "2,4");
+        assert_eq!(days_of_week("mon-FRI", 1).unwrap(), "2,3,4,5,6");
+        assert_eq!(days_of_week("Sun,Sat/1", 1).unwrap(), "1,7");
+        assert!(days_of_week("Monday", 1).is_err());
+        assert!(days_of_week("8", 1).is_err());
+        assert!(days_of_week("Fri-Mon", 1).is_err());
+    }
+
+    #[test]
+    fn parses_commands_and_variables() {
+        let entries = parse(
+            "# backups\n\
+             SHELL=/bin/sh\n\
+             BUCKET = \"backups\"\n\
+             \n\
+             30 3 * * 1-5 backup.sh --full\n\
+             @hourly echo 50\\% done\n",
+        )
+        .unwrap();
+
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].line, 5);
+        assert_eq!(entries[0].schedule, "0 30 3 * * 2,3,4,5,6");
+        assert_eq!(entries[0].command, "backup.sh --full");
+        assert_eq!(entries[0].env["BUCKET"], "backups");
+        assert!(!entries[0].env.contains_key("SHELL"));
+        assert_eq!(entries[1].schedule, "0 0 * * * *");
+        assert_eq!(entries[1].command, "echo 50% done");
+    }
+
+    #[test]
+    fn parse_rejects_what_dcron_runs_differently() {
+        assert!(parse("@reboot start.sh\n").is_err());
+        assert!(parse("SHELL=/bin/bash\n0 3 * * * [[ -f a ]] && a.sh\n").is_err());
+        assert!(parse("0 3 * * *\n").is_err());
+        assert!(parse("0 3 * * * printf '%s'\n").is_err());
+        // cron runs it on the 1st and on Mondays, dcron only on Mondays the 1st
+        assert!(parse("0 3 1 * Mon report.sh\n").is_err());
+        assert!(parse("0 3 * * * a.sh\n0 3 * * * a.sh\n").is_err());
+    }
+
+    fn imported(content: &str) -> Vec<JobRequest> {
+        let options = JobRequest {
+            timeout: 60,
+            env: HashMap::from([("PATH".into(), "/usr/bin".into())]),
+            ..Default::default()
+        };
+
+        parse(content)
+            .unwrap()
+            .iter()
+            .map(|entry| entry.to_request(&format!("jobs-{}", entry.id()), &options, "jobs"))
+            .collect()
+    }
+
+    fn job_status(job: &JobRequest) -> JobStatusResponse {
+        JobStatusResponse {
+            name: job.name.clone(),
+            time: job.time.clone(),
+            job_type: job.job_type,
+            timeout: job.timeout,
+            args: job.args.clone(),
+            env: job.env.clone(),
+            labels: job.labels.clone(),
+            script_digest: job.script_digest.clone(),
+            retention: job.retention.clone(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn importing_the_same_file_twice_changes_nothing() {
+        let first = imported("PATH=/bin\n0 3 * * * a.sh\n0 4 * * * b.sh\n");
+        // moving the lines around does not matter either
+        let second = imported("PATH=/bin\n\n# nightly\n0 4 * * * b.sh\n0 3 * * * a.sh\n");
+
+        for job in &second {
+            let current = first
+                .iter()
+                .find(|current| current.name == job.name)
+                .unwrap();
+            assert!(manifest::diff(job, &job_status(current)).is_empty());
+            assert_eq!(job.script_digest, current.script_digest);
+        }
+        assert_eq!(first[0].env["PATH"], "/bin");
+        assert_eq!(first[0].timeout, 60);
+    }
+
+    #[test]
+    fn changed_commands_are_new_jobs() {
+        let first = imported("FOO=1\n0 3 * * * a.sh\n");
+        let second = imported("FOO=2\n0 3 * * * a.sh --all\n0 3 * * * a.sh\n");
+
+        assert_ne!(first[0].name, second[0].name);
+        assert_eq!(first[0].name, second[1].name);
+        let fields = manifest::diff(&second[1], &job_status(&first[0]));
+        assert_eq!(fields.len(), 1);
+        assert_eq!(fields[0].field, "env");
+    }
+
+    #[test]
+    fn ids_do_not_depend_on_the_line() {
+        let before = parse("0 3 * * * a.sh\n").unwrap();
+        let after = parse("0 1 * * * b.sh\n\n0 3 * * * a.sh\n").unwrap();
+
+        assert_eq!(before[0].id(), after[1].id());
+        assert_ne!(after[0].id(), after[1].id());
+    }
+}
@@ -1,17 +1,71 @@
-use crate::{config::Config, heartbeat, job};
+use crate::{config::Config, event, heartbeat, job};
 use async_trait::async_trait;
 use chrono::Utc;
 use futures_util::TryStreamExt;
 use mongodb::{
-    bson::{doc, Document},
-    options::ClientOptions,
-    Client, Collection, Database,
+    bson::{doc, oid::ObjectId, to_bson, DateTime, Document},
+    error::{ErrorKind, WriteFailure},
+    options::{
+        ClientOptions, FindOneAndUpdateOptions, FindOneOptions, FindOptions, IndexOptions,
+        ReturnDocument, UpdateOptions,
+    },
+    Client, ClientSession, Database, IndexModel,
 };
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
 
-#[derive(Debug)]
-pub struct DBError {
-    pub message: String,
+// MongoDB server error codes we react to
+const ILLEGAL_OPERATION: i32 = 20;
+const DUPLICATE_KEY: i32 = 11000;
+const WRITE_CONFLICT: i32 = 112;
+
+// Labels the server adds when the whole transaction, or only its
+// commit, can be run again
+const TRANSIENT_TRANSACTION_ERROR: &str = "TransientTransactionError";
+const UNKNOWN_COMMIT_RESULT: &str = "UnknownTransactionCommitResult";
+const MAX_TRANSACTION_ATTEMPTS: u64 = 5;
+
+// Unique index allowing a single active job per name
+const ACTIVE_NAME_INDEX: &str = "unique_active_name";
+
+// Watching can resume from a cursor for this long
+const EVENTS_TTL: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+// Counter document the cursors of the events come from
+const EVENTS_COUNTER: &str = "events";
+
+// Indexes only need to be created once per process
+static INDEXES_CREATED: AtomicBool = AtomicBool::new(false);
+
+// Database errors are part of the errors shared with the services
+pub use crate::error::Error as DBError;
+
+impl From<mongodb::error::Error> for DBError {
+    fn from(error: mongodb::error::Error) -> Self {
+        let code = match &*error.kind {
+            ErrorKind::Write(WriteFailure::WriteError(write_error)) => Some(write_error.code),
+            ErrorKind::Command(command_error) => Some(command_error.code),
+            _ => None,
+        };
+
+        match (code, &*error.kind) {
+            (Some(DUPLICATE_KEY), _) | (Some(WRITE_CONFLICT), _) => {
+                DBError::Conflict(error.to_string())
+            }
+            // transactions on a standalone server
+            (Some(ILLEGAL_OPERATION), _) => DBError::failed_precondition(
+                "database",
+                &format!("transactions need a replica set: {}", error),
+            ),
+            (_, ErrorKind::ServerSelection { .. }) | (_, ErrorKind::Io(_)) => {
+                DBError::Unavailable(error.to_string())
+            }
+            _ => DBError::Internal(error.to_string()),
+        }
+    }
 }
+
 pub enum DBClient {
     MongoDB(mongodb::Client),
 }
@@ -20,27 +74,88 @@ pub struct MongoDBClient {
     client: Option<DBClient>,
 }
 
+// Filters for find_executions, results are sorted from the most
+// recent to the oldest execution
+#[derive(Debug, Default)]
+pub struct ExecutionFilter {
+    pub job_name: Option<String>,
+    // empty means any status
+    pub statuses: Vec<job::Status>,
+    pub started_after: Option<i64>,
+    pub started_before: Option<i64>,
+    // (start_time, id) of the last execution of the previous page
+    pub after: Option<(i64, String)>,
+    // how many of the most recent matches are left out
+    pub skip: u64,
+    pub limit: i64,
+}
+
 #[async_trait]
 pub trait DB {
-    async fn send_heartbeat(self: &Self, server_name: &str) -> Result<(), DBError>;
+    async fn send_heartbeat(&self, server_name: &str) -> Result<(), DBError>;
 
     async fn most_recent_heartbeat(
-        self: &Self,
+        &self,
     ) -> Result<Option<heartbeat::Heartbeat>, DBError>;
 
-    async fn find_job(self: &Self, name: &str, active: bool) -> Option<job::Job>;
+    async fn find_job(&self, name: &str, active: bool) -> Option<job::Job>;
 
-    async fn find_all_active(self: &Self) -> Result<Vec<job::Job>, DBError>;
+    async fn find_all_active(&self) -> Result<Vec<job::Job>, DBError>;
 
     async fn find_all_since(
-        self: &Self,
+        &self,
         active: bool,
         since: i64,
     ) -> Result<Vec<job::Job>, anyhow::Error>;
 
-    async fn disable_if_exist(self: &Self, name: &str) -> Result<(), DBError>;
+    async fn disable_if_exist(&self, name: &str) -> Result<(), DBError>;
+
+    // Atomically inserts the job as the active version for its name.
+    // If `replace` is set the current active version (if any) is disabled
+    // in the same transaction, otherwise `DBError::AlreadyExists` is returned
+    // when an active job with the same name already exists. The transaction
+    // is retried on write conflicts, `DBError::Conflict` is returned if a
+    // concurrent replace of the same job keeps winning.
+    async fn upsert_job(&self, job: &job::Job, replace: bool) -> Result<(), DBError>;
+
+    // Replaces `current`, the active version read before changing it, with
+    // `job`. `DBError::Conflict` is returned if it was changed meanwhile.
+    async fn update_job(&self, job: &job::Job, current: &job::Job) -> Result<(), DBError>;
+
+    // Sets (or clears with None) the pause of the active job in place,
+    // returns false if there is no active job with that name
+    async fn set_pause(&self, name: &str, pause: Option<job::Pause>) -> Result<bool, DBError>;
+
+    async fn insert_execution(&self, execution: &job::Execution) -> Result<(), DBError>;
+
+    async fn find_execution(&self, id: &str) -> Result<Option<job::Execution>, DBError>;
+
+    async fn find_latest_execution(
+        &self,
+        job_name: &str,
+    ) -> Result<Option<job::Execution>, DBError>;
+
+    // Replaces the execution with the same id
+    async fn update_execution(&self, execution: &job::Execution) -> Result<(), DBError>;
+
+    async fn find_executions(
+        &self,
+        filter: &ExecutionFilter,
+    ) -> Result<Vec<job::Execution>, DBError>;
+
+    // Names of the jobs with executions, including disabled jobs
+    async fn execution_job_names(&self) -> Result<Vec<String>, DBError>;
+
+    // Deletes by the id of the stored documents, which executions saved
+    // before `id` existed also have. Returns how many were deleted
+    async fn delete_executions(&self, ids: &[ObjectId]) -> Result<u64, DBError>;
+
+    // Events recorded after `cursor`, oldest first. Clients watch for
+    // changes by polling it with the cursor of the last event they got
+    async fn find_events(&self, cursor: i64, limit: i64) -> Result<Vec<event::Event>, DBError>;
 
-    async fn insert_if_not_exist(self: &Self, job: &job::Job) -> Result<(), DBError>;
+    // Cursor of the most recent event, 0 if there are no events
+    async fn latest_event_cursor(&self) -> Result<i64, DBError>;
 }
 
 impl MongoDBClient {
@@ -50,6 +165,8 @@ impl MongoDBClient {
             username, password, cluster_url
         )
     }
+    // Must be a replica set (a single node is fine), changes are written
+    // in transactions
     pub async fn local_connection() -> Result<Self, Box<dyn std::error::Error>> {
         Self::connect("mongodb://localhost:27017/dcron".into()).await
     }
@@ -63,37 +180,190 @@ impl MongoDBClient {
             .run_command(doc! {"ping": 1}, None)
             .await?;
 
+        if let Err(e) = Self::create_indexes(&client).await {
+            return Err(e.to_string().into());
+        }
+
         Ok(Self {
             client: Some(DBClient::MongoDB(client)),
         })
     }
-    fn get_db(self: &Self) -> Option<Database> {
+    fn get_db(&self) -> Option<Database> {
         if let Some(DBClient::MongoDB(client)) = &self.client {
             return Some(client.database("dcron"));
         }
         None
     }
-    async fn insert(self: &Self, job: &job::Job) -> Result<(), DBError> {
-        if let Some(database) = self.get_db() {
-            let collection = database.collection("jobs");
-            return match collection
-                    .insert_one(
-                        doc! { "name": &job.name, "script_type": &job.script, "script": &job.script, "time": &job.time, "timeout": &job.timeout,
-                        "updated_at": &job.updated_at},
-                        None,
-                    )
-                    .await {
-                Ok(_) => Ok(()),
-                Err(e) => Err(DBError{message: e.to_string()})
+
+    fn get_client(&self) -> Result<&Client, DBError> {
+        match &self.client {
+            Some(DBClient::MongoDB(client)) => Ok(client),
+            None => Err(DBError::Unavailable("Could not connect to the database".into())),
+        }
+    }
+
+    // Only one active job per name is allowed, older versions are kept
+    // with active=false
+    async fn create_indexes(client: &Client) -> Result<(), DBError> {
+        if INDEXES_CREATED.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+
+        let index = IndexModel::builder()
+            .keys(doc! {"name": 1})
+            .options(
+                IndexOptions::builder()
+                    .name(Some(ACTIVE_NAME_INDEX.to_string()))
+                    .unique(true)
+                    .partial_filter_expression(doc! {"active": true})
+                    .build(),
+            )
+            .build();
+
+        client
+            .database("dcron")
+            .collection::<job::Job>("jobs")
+            .create_index(index, None)
+            .await?;
+
+        let executions = vec![
+            // executions recorded before they had ids are left out
+            IndexModel::builder()
+                .keys(doc! {"id": 1})
+                .options(
+                    IndexOptions::builder()
+                        .name(Some("unique_execution_id".to_string()))
+                        .unique(true)
+                        .partial_filter_expression(doc! {"id": {"$exists": true}})
+                        .build(),
+                )
+                .build(),
+            // used by find_executions, filtered by job or not
+            IndexModel::builder()
+                .keys(doc! {"job_name": 1, "start_time": -1, "id": -1})
+                .build(),
+            IndexModel::builder()
+                .keys(doc! {"start_time": -1, "id": -1})
+                .build(),
+        ];
+
+        client
+            .database("dcron")
+            .collection::<job::Execution>("executions")
+            .create_indexes(executions, None)
+            .await?;
+
+        let events = vec![
+            IndexModel::builder()
+                .keys(doc! {"cursor": 1})
+                .options(IndexOptions::builder().unique(true).build())
+                .build(),
+            IndexModel::builder()
+                .keys(doc! {"created_at": 1})
+                .options(IndexOptions::builder().expire_after(EVENTS_TTL).build())
+                .build(),
+        ];
+
+        client
+            .database("dcron")
+            .collection::<event::Event>("events")
+            .create_indexes(events, None)
+            .await?;
+
+        // created outside of the transactions that increment it, two of
+        // them creating it at once would conflict
+        client
+            .database("dcron")
+            .collection::<Document>("counters")
+            .update_one(
+                doc! {"_id": EVENTS_COUNTER},
+                doc! {"$setOnInsert": {"seq": 0_i64}},
+                UpdateOptions::builder().upsert(true).build(),
+            )
+            .await?;
+
+        INDEXES_CREATED.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+impl MongoDBClient {
+    // Disables the active version of the job and inserts the new one.
+    // With `current` only that version is replaced, `DBError::Conflict`
+    // is returned if it changed.
+    async fn replace_job(&self, job: &job::Job, current: Option<&job::Job>) -> Result<(), DBError> {
+        let client = self.get_client()?;
+
+        let replaced = with_retries(|| async move {
+            let database = client.database("dcron");
+            let collection = database.collection::<job::Job>("jobs");
+            let mut session = start_transaction(client).await?;
+
+            // set_pause changes the version in place, hence updated_at
+            let filter = match current {
+                Some(current) => {
+                    doc! {"_id": current.id, "active": true, "updated_at": current.updated_at}
+                }
+                None => doc! {"name": &job.name, "active": true},
+            };
+            let replaced = collection
+                .update_one_with_session(
+                    filter,
+                    doc! {"$set": {"active": false, "updated_at": job.updated_at}},
+                    None,
+                    &mut session,
+                )
+                .await?;
+
+            if current.is_some() && replaced.matched_count == 0 {
+                session.abort_transaction().await?;
+                return Ok(false);
+            }
+
+            collection
+                .insert_one_with_session(job, None, &mut session)
+                .await?;
+
+            let event_type = match replaced.matched_count {
+                0 => event::EventType::JobCreated,
+                _ => event::EventType::JobUpdated,
             };
+            insert_event(
+                &database,
+                &mut session,
+                event_type,
+                &job.name,
+                job.labels.clone(),
+                None,
+            )
+            .await?;
+
+            commit(&mut session).await?;
+            Ok(true)
+        })
+        .await?;
+
+        match replaced {
+            true => Ok(()),
+            false => Err(DBError::Conflict(format!(
+                "job {} was changed since it was read",
+                job.name
+            ))),
+        }
+    }
+
+    // Labels of the active job, copied to the events of its executions
+    async fn job_labels(&self, name: &str) -> HashMap<String, String> {
+        match self.find_job(name, true).await {
+            Some(job) => job.labels,
+            None => HashMap::new(),
         }
-        Err(DBError{message: "Unknown error while saving the Job".into()})
     }
 }
 
 #[async_trait]
 impl DB for MongoDBClient {
-    async fn send_heartbeat(self: &Self, server_name: &str) -> Result<(), DBError> {
+    async fn send_heartbeat(&self, server_name: &str) -> Result<(), DBError> {
         if let Some(database) = self.get_db() {
             let collection = database.collection("heartbeats");
             return match collection
@@ -103,14 +373,14 @@ impl DB for MongoDBClient {
                 )
                 .await {
                 Ok(_) => Ok(()),
-                Err(e) => Err(DBError{message: e.to_string()})
+                Err(e) => Err(e.into())
             };
         }
-        Err(DBError{message: "Unknown error while saving heartbeats".into()})
+        Err(DBError::Unavailable("Could not get the database object".into()))
     }
 
     async fn most_recent_heartbeat(
-        self: &Self,
+        &self,
     ) -> Result<Option<heartbeat::Heartbeat>, DBError> {
         if let Some(database) = self.get_db() {
             let collection = database.collection::<heartbeat::Heartbeat>("heartbeats");
@@ -129,10 +399,10 @@ impl DB for MongoDBClient {
                 _ => Ok(None),
             }
         }
-        Err(DBError{message: "Could not get a heartbeat".to_string()})
+        Err(DBError::Unavailable("Could not get the database object".into()))
     }
 
-    async fn find_job(self: &Self, name: &str, active: bool) -> Option<job::Job> {
+    async fn find_job(&self, name: &str, active: bool) -> Option<job::Job> {
         if let Some(database) = self.get_db() {
             let collection = database.collection::<job::Job>("jobs");
 
@@ -147,7 +417,7 @@ impl DB for MongoDBClient {
         }
         None
     }
-    async fn find_all_active(self: &Self) -> Result<Vec<job::Job>, DBError> {
+    async fn find_all_active(&self) -> Result<Vec<job::Job>, DBError> {
         return match self.get_db() {
             Some(database) => {
                 let collection = database.collection::<job::Job>("jobs");
@@ -155,23 +425,23 @@ impl DB for MongoDBClient {
 
                 let result = match jobs_cursor {
                     Ok(jobs_cursor) => jobs_cursor.try_collect().await,
-                    Err(e) => return Err(DBError{message: e.to_string()}),
+                    Err(e) => return Err(e.into()),
                 };
 
                 match result {
                     Ok(result) => Ok(result),
-                    Err(e) => Err(DBError{message: e.to_string()})
+                    Err(e) => Err(e.into())
                 }
 
             }
             None => {
-                Err(DBError{message: "Could not connect to the database".to_string()})
+                Err(DBError::Unavailable("Could not connect to the database".into()))
             }
         };
     }
 
     async fn find_all_since(
-        self: &Self,
+        &self,
         active: bool,
         since: i64,
     ) -> Result<Vec<job::Job>, anyhow::Error> {
@@ -189,32 +459,457 @@ impl DB for MongoDBClient {
         }
     }
 
-    async fn disable_if_exist(self: &Self, name: &str) -> Result<(), DBError> {
-        if let Some(database) = self.get_db() {
-            if let Some(_job) = self.find_job(name, true).await {
-                let collection: Collection<Document> = database.collection("jobs");
-                return match collection
-                    .update_one(
-                        doc! {"name": name, "active": true},
-                        doc! {"active": false, "updated_at": Utc::now().timestamp()},
-                        None,
-                    )
-                    .await {
-                    Ok(_) => Ok(()),
-                    Err(e) => Err(DBError{message: e.to_string()})
-                };
+    async fn disable_if_exist(&self, name: &str) -> Result<(), DBError> {
+        let client = self.get_client()?;
+
+        with_retries(|| async move {
+            let database = client.database("dcron");
+            let mut session = start_transaction(client).await?;
+
+            let job = database
+                .collection::<job::Job>("jobs")
+                .find_one_and_update_with_session(
+                    doc! {"name": name, "active": true},
+                    doc! {"$set": {"active": false, "updated_at": Utc::now().timestamp()}},
+                    None,
+                    &mut session,
+                )
+                .await?;
+
+            if let Some(job) = job {
+                insert_event(
+                    &database,
+                    &mut session,
+                    event::EventType::JobDisabled,
+                    name,
+                    job.labels,
+                    None,
+                )
+                .await?;
             }
-        } else {
-            return Err(DBError{message: "Could not get the database object".to_string()});
+
+            commit(&mut session).await
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn upsert_job(&self, job: &job::Job, replace: bool) -> Result<(), DBError> {
+        if replace {
+            return self.replace_job(job, None).await;
+        }
+
+        let client = self.get_client()?;
+        let inserted = with_retries(|| async move {
+            let database = client.database("dcron");
+            let mut session = start_transaction(client).await?;
+
+            // the unique partial index makes this fail if there is
+            // already an active job with the same name
+            database
+                .collection::<job::Job>("jobs")
+                .insert_one_with_session(job, None, &mut session)
+                .await?;
+
+            insert_event(
+                &database,
+                &mut session,
+                event::EventType::JobCreated,
+                &job.name,
+                job.labels.clone(),
+                None,
+            )
+            .await?;
+
+            commit(&mut session).await
+        })
+        .await;
+
+        // a write conflict left after the retries stays a conflict, the
+        // create can be tried again
+        match inserted {
+            Err(e) if is_duplicate_key(&e, ACTIVE_NAME_INDEX) => {
+                Err(DBError::already_exists("job", &job.name))
+            }
+            result => Ok(result?),
+        }
+    }
+
+    async fn update_job(&self, job: &job::Job, current: &job::Job) -> Result<(), DBError> {
+        self.replace_job(job, Some(current)).await
+    }
+
+    async fn set_pause(&self, name: &str, pause: Option<job::Pause>) -> Result<bool, DBError> {
+        let client = self.get_client()?;
+        let pause = match to_bson(&pause) {
+            Ok(pause) => pause,
+            Err(e) => return Err(DBError::Internal(e.to_string())),
+        };
+        let pause = &pause;
+
+        let updated = with_retries(|| async move {
+            let database = client.database("dcron");
+            let mut session = start_transaction(client).await?;
+
+            // updated_at is bumped so the leader picks up the new version
+            let options = FindOneAndUpdateOptions::builder()
+                .return_document(ReturnDocument::After)
+                .build();
+            let job = database
+                .collection::<job::Job>("jobs")
+                .find_one_and_update_with_session(
+                    doc! {"name": name, "active": true},
+                    doc! {"$set": {"pause": pause, "updated_at": Utc::now().timestamp()}},
+                    options,
+                    &mut session,
+                )
+                .await?;
+
+            let job = match job {
+                Some(job) => job,
+                None => return Ok(false),
+            };
+            insert_event(
+                &database,
+                &mut session,
+                event::EventType::JobUpdated,
+                name,
+                job.labels,
+                None,
+            )
+            .await?;
+
+            commit(&mut session).await?;
+            Ok(true)
+        })
+        .await?;
+
+        Ok(updated)
+    }
+
+    async fn insert_execution(&self, execution: &job::Execution) -> Result<(), DBError> {
+        let client = self.get_client()?;
+        let labels = &self.job_labels(&execution.job_name).await;
+
+        with_retries(|| async move {
+            let database = client.database("dcron");
+            let mut session = start_transaction(client).await?;
+
+            database
+                .collection::<job::Execution>("executions")
+                .insert_one_with_session(execution, None, &mut session)
+                .await?;
+
+            // skipped executions are recorded already finished
+            let event_type = match execution.status {
+                job::Status::RUNNING => event::EventType::ExecutionStarted,
+                _ => event::EventType::ExecutionFinished,
+            };
+            insert_event(
+                &database,
+                &mut session,
+                event_type,
+                &execution.job_name,
+                labels.clone(),
+                Some(execution),
+            )
+            .await?;
+
+            commit(&mut session).await
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn find_execution(&self, id: &str) -> Result<Option<job::Execution>, DBError> {
+        if let Some(database) = self.get_db() {
+            let collection = database.collection::<job::Execution>("executions");
+            return match collection.find_one(doc! {"id": id}, None).await {
+                Ok(execution) => Ok(execution),
+                Err(e) => Err(e.into()),
+            };
         }
+        Err(DBError::Unavailable("Could not get the database object".into()))
+    }
+
+    async fn find_latest_execution(
+        &self,
+        job_name: &str,
+    ) -> Result<Option<job::Execution>, DBError> {
+        if let Some(database) = self.get_db() {
+            let collection = database.collection::<job::Execution>("executions");
+            let options = FindOneOptions::builder()
+                .sort(doc! {"start_time": -1})
+                .build();
+            return match collection
+                .find_one(doc! {"job_name": job_name}, options)
+                .await
+            {
+                Ok(execution) => Ok(execution),
+                Err(e) => Err(e.into()),
+            };
+        }
+        Err(DBError::Unavailable("Could not get the database object".into()))
+    }
+
+    async fn update_execution(&self, execution: &job::Execution) -> Result<(), DBError> {
+        let client = self.get_client()?;
+        let labels = &self.job_labels(&execution.job_name).await;
+
+        with_retries(|| async move {
+            let database = client.database("dcron");
+            let mut session = start_transaction(client).await?;
+
+            database
+                .collection::<job::Execution>("executions")
+                .replace_one_with_session(doc! {"id": &execution.id}, execution, None, &mut session)
+                .await?;
+
+            if execution.status != job::Status::RUNNING {
+                insert_event(
+                    &database,
+                    &mut session,
+                    event::EventType::ExecutionFinished,
+                    &execution.job_name,
+                    labels.clone(),
+                    Some(execution),
+                )
+                .await?;
+            }
+
+            commit(&mut session).await
+        })
+        .await?;
         Ok(())
     }
 
-    async fn insert_if_not_exist(self: &Self, job: &job::Job) -> Result<(), DBError> {
-        if let Some(_job) = self.find_job(&job.name, true).await {
-            Err(DBError{message: "Job already in database".into()})
-        } else {
-            self.insert(job).await
+    async fn find_executions(
+        &self,
+        filter: &ExecutionFilter,
+    ) -> Result<Vec<job::Execution>, DBError> {
+        let database = match self.get_db() {
+            Some(database) => database,
+            None => return Err(DBError::Unavailable("Could not get the database object".into())),
+        };
+
+        let mut query = doc! {};
+        if let Some(job_name) = &filter.job_name {
+            query.insert("job_name", job_name);
+        }
+        if !filter.statuses.is_empty() {
+            let statuses = match to_bson(&filter.statuses) {
+                Ok(statuses) => statuses,
+                Err(e) => return Err(DBError::Internal(e.to_string())),
+            };
+            query.insert("status", doc! {"$in": statuses});
+        }
+
+        let mut start_time = doc! {};
+        if let Some(started_after) = filter.started_after {
+            start_time.insert("$gte", started_after);
+        }
+        if let Some(started_before) = filter.started_before {
+            start_time.insert("$lt", started_before);
+        }
+        if !start_time.is_empty() {
+            query.insert("start_time", start_time);
+        }
+
+        // keyset pagination, the page starts right after the last
+        // execution of the previous one
+        if let Some((start_time, id)) = &filter.after {
+            query.insert(
+                "$or",
+                vec![
+                    doc! {"start_time": {"$lt": start_time}},
+                    doc! {"start_time": start_time, "id": {"$lt": id}},
+                ],
+            );
+        }
+
+        let options = FindOptions::builder()
+            .sort(doc! {"start_time": -1, "id": -1})
+            .skip(filter.skip)
+            .limit(filter.limit)
+            .build();
+
+        let cursor = database
+            .collection::<job::Execution>("executions")
+            .find(query, options)
+            .await?;
+
+        Ok(cursor.try_collect().await?)
+    }
+
+    async fn execution_job_names(&self) -> Result<Vec<String>, DBError> {
+        let database = match self.get_db() {
+            Some(database) => database,
+            None => return Err(DBError::Unavailable("Could not get the database object".into())),
+        };
+
+        let names = database
+            .collection::<job::Execution>("executions")
+            .distinct("job_name", None, None)
+            .await?;
+
+        Ok(names
+            .iter()
+            .filter_map(|name| name.as_str().map(String::from))
+            .collect())
+    }
+
+    async fn delete_executions(&self, ids: &[ObjectId]) -> Result<u64, DBError> {
+        let database = match self.get_db() {
+            Some(database) => database,
+            None => return Err(DBError::Unavailable("Could not get the database object".into())),
+        };
+
+        let result = database
+            .collection::<job::Execution>("executions")
+            .delete_many(doc! {"_id": {"$in": ids}}, None)
+            .await?;
+
+        Ok(result.deleted_count)
+    }
+
+    async fn find_events(&self, cursor: i64, limit: i64) -> Result<Vec<event::Event>, DBError> {
+        let database = match self.get_db() {
+            Some(database) => database,
+            None => return Err(DBError::Unavailable("Could not get the database object".into())),
+        };
+
+        let options = FindOptions::builder()
+            .sort(doc! {"cursor": 1})
+            .limit(limit)
+            .build();
+
+        let events = database
+            .collection::<event::Event>("events")
+            .find(doc! {"cursor": {"$gt": cursor}}, options)
+            .await?;
+
+        Ok(events.try_collect().await?)
+    }
+
+    async fn latest_event_cursor(&self) -> Result<i64, DBError> {
+        let database = match self.get_db() {
+            Some(database) => database,
+            None => return Err(DBError::Unavailable("Could not get the database object".into())),
+        };
+
+        let counter = database
+            .collection::<Document>("counters")
+            .find_one(doc! {"_id": EVENTS_COUNTER}, None)
+            .await?;
+
+        Ok(counter
+            .and_then(|counter| counter.get_i64("seq").ok())
+            .unwrap_or(0))
+    }
+}
+
+// Runs the transaction again while the server says it can be, e.g.
+// after a write conflict with a concurrent one. Every change that
+// records an event increments the same counter, so they conflict often.
+async fn with_retries<T, F, Fut>(mut transaction: F) -> mongodb::error::Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = mongodb::error::Result<T>>,
+{
+    let mut attempts = 0;
+    loop {
+        attempts += 1;
+        match transaction().await {
+            Err(e)
+                if e.contains_label(TRANSIENT_TRANSACTION_ERROR)
+                    && attempts < MAX_TRANSACTION_ATTEMPTS =>
+            {
+                tokio::time::sleep(Duration::from_millis(10 * attempts)).await;
+            }
+            result => return result,
+        }
+    }
+}
+
+// The server only names the index in the message of the error
+fn is_duplicate_key(error: &mongodb::error::Error, index: &str) -> bool {
+    match &*error.kind {
+        ErrorKind::Write(WriteFailure::WriteError(write_error)) => {
+            write_error.code == DUPLICATE_KEY && write_error.message.contains(index)
+        }
+        ErrorKind::Command(command_error) => {
+            command_error.code == DUPLICATE_KEY && command_error.message.contains(index)
+        }
+        _ => false,
+    }
+}
+
+// If anything fails before the commit the session is dropped and the
+// transaction aborted, nothing it wrote is kept
+async fn start_transaction(client: &Client) -> mongodb::error::Result<ClientSession> {
+    let mut session = client.start_session(None).await?;
+    session.start_transaction(None).await?;
+    Ok(session)
+}
+
+// The cursor comes from a counter incremented in the transaction of the
+// change. Transactions incrementing it one after the other cannot commit
+// out of order, so watchers never skip an event committed late.
+async fn insert_event(
+    database: &Database,
+    session: &mut ClientSession,
+    event_type: event::EventType,
+    job_name: &str,
+    labels: HashMap<String, String>,
+    execution: Option<&job::Execution>,
+) -> mongodb::error::Result<()> {
+    let options = FindOneAndUpdateOptions::builder()
+        .upsert(true)
+        .return_document(ReturnDocument::After)
+        .build();
+    let counter = database
+        .collection::<Document>("counters")
+        .find_one_and_update_with_session(
+            doc! {"_id": EVENTS_COUNTER},
+            doc! {"$inc": {"seq": 1_i64}},
+            options,
+            session,
+        )
+        .await?;
+
+    let event = event::Event {
+        cursor: counter
+            .and_then(|counter| counter.get_i64("seq").ok())
+            .unwrap_or(0),
+        event_type,
+        job_name: job_name.into(),
+        labels,
+        execution_id: execution.map(|execution| execution.id.clone()),
+        status: execution.map(|execution| execution.status.clone()),
+        created_at: DateTime::now(),
+    };
+
+    database
+        .collection::<event::Event>("events")
+        .insert_one_with_session(&event, None, session)
+        .await?;
+    Ok(())
+}
+
+// The commit itself can be retried when its outcome is unknown,
+// e.g. after a network error
+async fn commit(session: &mut ClientSession) -> mongodb::error::Result<()> {
+    let mut attempts = 0;
+    loop {
+        attempts += 1;
+        match session.commit_transaction().await {
+            Err(e)
+                if e.contains_label(UNKNOWN_COMMIT_RESULT)
+                    && attempts < MAX_TRANSACTION_ATTEMPTS =>
+            {
+                continue
+            }
+            result => return result,
         }
     }
 }
@@ -233,14 +928,14 @@ pub async fn get_db(
 
         match MongoDBClient::connect(url).await {
             Ok(c) => Ok(Box::new(c)),
-            Err(e) => Err(DBError{message: e.to_string()})
+            Err(e) => Err(DBError::Unavailable(e.to_string()))
         }
 
 
     } else {
         match MongoDBClient::local_connection().await {
             Ok(c) => Ok(Box::new(c)),
-            Err(e) => Err(DBError{message: e.to_string()})
+            Err(e) => Err(DBError::Unavailable(e.to_string()))
         }
     }
 }
@@ -0,0 +1,278 @@
+use crate::config;
+use crate::error::Error;
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use aes_gcm::{Aes256Gcm, Nonce};
+use std::collections::HashMap;
+use std::fs;
+
+// Object metadata with the id of the key the data key is encrypted with
+const KEY_ID: &str = "dcron-key-id";
+// the key the object is encrypted with, itself encrypted, hex encoded
+const DATA_KEY: &str = "dcron-data-key";
+
+const NONCE_SIZE: usize = 12;
+
+// Stored with each object, user defined headers on S3
+pub type Metadata = HashMap<String, String>;
+
+// Envelope encryption: every object is encrypted with a key of its own,
+// stored next to it encrypted with a configured key. Rotating the
+// configured key only needs the old one kept to read the old objects.
+pub struct Keys {
+    key_id: String,
+    keys: HashMap<String, Aes256Gcm>,
+    allow_plaintext: bool,
+}
+
+impl Keys {
+    pub fn load(config: &config::Encryption) -> Result<Keys, Error> {
+        let mut keys = HashMap::new();
+        keys.insert(
+            config.key_id.clone(),
+            cipher(&config.key_id, &config.key, &config.key_file)?,
+        );
+        for key in &config.old_keys {
+            keys.insert(key.id.clone(), cipher(&key.id, &key.key, &key.key_file)?);
+        }
+
+        Ok(Keys {
+            key_id: config.key_id.clone(),
+            keys,
+            allow_plaintext: config.allow_plaintext,
+        })
+    }
+
+    // The content is bound to `name`, it cannot be read back from another object
+    pub fn encrypt(&self, name: &str, content: &[u8]) -> Result<(Vec<u8>, Metadata), Error> {
+        let data_key = Aes256Gcm::generate_key(&mut OsRng);
+        let encrypted = seal(&Aes256Gcm::new(&data_key), content, name.as_bytes())?;
+        // the key id is authenticated so it cannot be swapped for another
+        let wrapped = seal(
+            &self.keys[&self.key_id],
+            &data_key[..],
+            self.key_id.as_bytes(),
+        )?;
+
+        let mut metadata = Metadata::new();
+        metadata.insert(KEY_ID.into(), self.key_id.clone());
+        metadata.insert(DATA_KEY.into(), hex::encode(wrapped));
+        Ok((encrypted, metadata))
+    }
+
+    // Objects stored before encryption was enabled are returned as they are
+    // when the config allows plaintext
+    pub fn decrypt(
+        &self,
+        name: &str,
+        content: Vec<u8>,
+        metadata: &Metadata,
+    ) -> Result<Vec<u8>, Error> {
+        let key_id = match metadata.get(KEY_ID) {
+            Some(key_id) => key_id,
+            None if self.allow_plaintext => return Ok(content),
+            None => {
+                return Err(Error::failed_precondition(
+                    "encryption",
+                    "the object is not encrypted and the config does not allow plaintext",
+                ))
+            }
+        };
+        let key = match self.keys.get(key_id) {
+            Some(key) => key,
+            None => {
+                return Err(Error::failed_precondition(
+                    "encryption",
+                    &format!(
+                        "the object is encrypted with key {}, not in the config",
+                        key_id
+                    ),
+                ))
+            }
+        };
+        let wrapped = match metadata.get(DATA_KEY).map(hex::decode) {
+            Some(Ok(wrapped)) => wrapped,
+            _ => return Err(Error::internal("the object has no valid data key")),
+        };
+
+        let data_key = open(key, &wrapped, key_id.as_bytes())?;
+        match Aes256Gcm::new_from_slice(&data_key) {
+            Ok(cipher) => open(&cipher, &content, name.as_bytes()),
+            Err(_) => Err(Error::internal("the object has no valid data key")),
+        }
+    }
+}
+
+pub fn is_encrypted(metadata: &Metadata) -> bool {
+    metadata.contains_key(KEY_ID)
+}
+
+fn cipher(id: &str, key: &Option<String>, key_file: &Option<String>) -> Result<Aes256Gcm, Error> {
+    let key = match (key, key_file) {
+        (Some(key), None) => key.clone(),
+        (None, Some(key_file)) => match fs::read_to_string(key_file) {
+            Ok(key) => key,
+            Err(e) => return Err(Error::Internal(format!("{}: {}", key_file, e))),
+        },
+        _ => {
+            return Err(Error::Internal(format!(
+                "encryption key {} needs either key or key_file",
+                id
+            )))
+        }
+    };
+
+    match hex::decode(key.trim()).map(|key| Aes256Gcm::new_from_slice(&key)) {
+        Ok(Ok(cipher)) => Ok(cipher),
+        _ => Err(Error::Internal(format!(
+            "encryption key {} is not 32 hex encoded bytes",
+            id
+        ))),
+    }
+}
+
+// The random nonce is prepended to the encrypted content
+fn seal(cipher: &Aes256Gcm, content: &[u8], aad: &[u8]) -> Result<Vec<u8>, Error> {
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    match cipher.encrypt(&nonce, Payload { msg: content, aad }) {
+        Ok(encrypted) => {
+            let mut sealed = nonce.to_vec();
+            sealed.extend(encrypted);
+            Ok(sealed)
+        }
+        Err(_) => Err(Error::internal("could not encrypt the object")),
+    }
+}
+
+fn open(cipher: &Aes256Gcm, sealed: &[u8], aad: &[u8]) -> Result<Vec<u8>, Error> {
+    let (nonce, encrypted) = match sealed.split_first_chunk::<NONCE_SIZE>() {
+        Some(split) => split,
+        None => return Err(Error::internal("the encrypted object is truncated")),
+    };
+
+    match cipher.decrypt(
+        &Nonce::from(*nonce),
+        Payload {
+            msg: encrypted,
+            aad,
+        },
+    ) {
+        Ok(content) => Ok(content),
+        Err(_) => Err(Error::internal(
+            "could not decrypt the object, it was changed or the key is wrong",
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys(key_id: &str, old_keys: &[&str], allow_plaintext: bool) -> Keys {
+        // ids of one letter, repeated to make 32 bytes
+        let key = |id: &str| Some(hex::encode(id.repeat(32)));
+        Keys::load(&config::Encryption {
+            key_id: key_id.into(),
+            key: key(key_id),
+            key_file: None,
+            old_keys: old_keys
+                .iter()
+                .map(|id| config::Key {
+                    id: id.to_string(),
+                    key: key(id),
+                    key_file: None,
+                })
+                .collect(),
+            allow_plaintext,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn round_trip() {
+        let keys = keys("a", &[], false);
+
+        let (encrypted, metadata) = keys.encrypt("scripts/a", b"echo hello").unwrap();
+        assert!(is_encrypted(&metadata));
+        assert_eq!(metadata[KEY_ID], "a");
+        assert!(!encrypted
+            .windows(b"echo hello".len())
+            .any(|window| window == b"echo hello"));
+
+        let decrypted = keys.decrypt("scripts/a", encrypted, &metadata).unwrap();
+        assert_eq!(decrypted, b"echo hello");
+    }
+
+    #[test]
+    fn old_keys_read_objects_written_before_a_rotation() {
+        let (encrypted, metadata) = keys("a", &[], false)
+            .encrypt("scripts/a", b"echo hello")
+            .unwrap();
+
+        let rotated = keys("b", &["a"], false);
+        let decrypted = rotated
+            .decrypt("scripts/a", encrypted.clone(), &metadata)
+            .unwrap();
+        assert_eq!(decrypted, b"echo hello");
+        // new objects use the new key
+        assert_eq!(rotated.encrypt("scripts/b", b"").unwrap().1[KEY_ID], "b");
+
+        // once the old key is dropped its objects cannot be read
+        let result = keys("b", &[], false).decrypt("scripts/a", encrypted, &metadata);
+        assert!(matches!(result, Err(Error::FailedPrecondition { .. })));
+    }
+
+    #[test]
+    fn changes_are_detected() {
+        let keys = keys("a", &["b"], false);
+        let (encrypted, metadata) = keys.encrypt("scripts/a", b"echo hello").unwrap();
+
+        let mut changed = encrypted.clone();
+        *changed.last_mut().unwrap() ^= 1;
+        assert!(keys.decrypt("scripts/a", changed, &metadata).is_err());
+
+        let truncated = encrypted[..NONCE_SIZE - 1].to_vec();
+        assert!(keys.decrypt("scripts/a", truncated, &metadata).is_err());
+
+        // the content of another object
+        assert!(keys
+            .decrypt("scripts/b", encrypted.clone(), &metadata)
+            .is_err());
+
+        // the data key wrapped by another key
+        let mut swapped = metadata.clone();
+        swapped.insert(KEY_ID.into(), "b".into());
+        assert!(keys
+            .decrypt("scripts/a", encrypted.clone(), &swapped)
+            .is_err());
+
+        let mut changed = metadata.clone();
+        let mut data_key = hex::decode(&metadata[DATA_KEY]).unwrap();
+        data_key[0] ^= 1;
+        changed.insert(DATA_KEY.into(), hex::encode(data_key));
+        assert!(keys.decrypt("scripts/a", encrypted, &changed).is_err());
+    }
+
+    #[test]
+    fn plaintext_is_only_read_when_allowed() {
+        let result = keys("a", &[], false).decrypt("scripts/a", b"echo".to_vec(), &Metadata::new());
+        assert!(matches!(result, Err(Error::FailedPrecondition { .. })));
+
+        let result = keys("a", &[], true).decrypt("scripts/a", b"echo".to_vec(), &Metadata::new());
+        assert_eq!(result.unwrap(), b"echo");
+    }
+
+    #[test]
+    fn keys_must_be_32_bytes() {
+        let config = |key: &str| config::Encryption {
+            key_id: "a".into(),
+            key: Some(key.into()),
+            key_file: None,
+            old_keys: vec![],
+            allow_plaintext: false,
+        };
+
+        assert!(Keys::load(&config(&"ab".repeat(32))).is_ok());
+        assert!(Keys::load(&config(&"ab".repeat(16))).is_err());
+        assert!(Keys::load(&config("not hex")).is_err());
+    }
+}
@@ -0,0 +1,211 @@
+use prost::Message;
+use std::fmt;
+use tonic::{Code, Status};
+
+pub mod rpc {
+    tonic::include_proto!("google.rpc");
+}
+
+pub use rpc::bad_request::FieldViolation;
+
+// ErrorInfo.domain of every error sent by dcron
+pub const DOMAIN: &str = "dcron";
+
+// Errors shared by the database, the object storage and the gRPC services.
+// Each variant maps to one gRPC code and to the google.rpc details
+// clients can use to react to it.
+#[derive(Debug)]
+pub enum Error {
+    NotFound {
+        resource_type: String,
+        name: String,
+    },
+    // e.g. an active job with the same name
+    AlreadyExists {
+        resource_type: String,
+        name: String,
+    },
+    // the request has fields with invalid values, e.g. a bad cron expression
+    InvalidArgument(Vec<FieldViolation>),
+    // the resource is not in a state that allows the operation,
+    // e.g. cancelling an execution that already finished
+    FailedPrecondition {
+        subject: String,
+        description: String,
+    },
+    // a concurrent change won, the client can retry
+    Conflict(String),
+    // the database, a worker or the object storage could not be reached
+    Unavailable(String),
+    Internal(String),
+}
+
+impl Error {
+    pub fn not_found(resource_type: &str, name: &str) -> Self {
+        Error::NotFound {
+            resource_type: resource_type.into(),
+            name: name.into(),
+        }
+    }
+
+    pub fn already_exists(resource_type: &str, name: &str) -> Self {
+        Error::AlreadyExists {
+            resource_type: resource_type.into(),
+            name: name.into(),
+        }
+    }
+
+    pub fn invalid(field: &str, description: &str) -> Self {
+        Error::InvalidArgument(vec![FieldViolation {
+            field: field.into(),
+            description: description.into(),
+        }])
+    }
+
+    pub fn failed_precondition(subject: &str, description: &str) -> Self {
+        Error::FailedPrecondition {
+            subject: subject.into(),
+            description: description.into(),
+        }
+    }
+
+    pub fn internal(message: &str) -> Self {
+        Error::Internal(message.into())
+    }
+
+    pub fn code(&self) -> Code {
+        match self {
+            Error::NotFound { .. } => Code::NotFound,
+            Error::AlreadyExists { .. } => Code::AlreadyExists,
+            Error::InvalidArgument(_) => Code::InvalidArgument,
+            Error::FailedPrecondition { .. } => Code::FailedPrecondition,
+            Error::Conflict(_) => Code::Aborted,
+            Error::Unavailable(_) => Code::Unavailable,
+            Error::Internal(_) => Code::Internal,
+        }
+    }
+
+    // ErrorInfo.reason, stable so clients can match on it
+    pub fn reason(&self) -> &'static str {
+        match self {
+            Error::NotFound { .. } => "NOT_FOUND",
+            Error::AlreadyExists { .. } => "ALREADY_EXISTS",
+            Error::InvalidArgument(_) => "INVALID_ARGUMENT",
+            Error::FailedPrecondition { .. } => "FAILED_PRECONDITION",
+            Error::Conflict(_) => "CONFLICT",
+            Error::Unavailable(_) => "UNAVAILABLE",
+            Error::Internal(_) => "INTERNAL",
+        }
+    }
+
+    fn details(&self) -> Vec<prost_types::Any> {
+        let mut details = vec![any(
+            "google.rpc.ErrorInfo",
+            &rpc::ErrorInfo {
+                reason: self.reason().into(),
+                domain: DOMAIN.into(),
+                metadata: Default::default(),
+            },
+        )];
+
+        match self {
+            Error::NotFound {
+                resource_type,
+                name,
+            }
+            | Error::AlreadyExists {
+                resource_type,
+                name,
+            } => details.push(any(
+                "google.rpc.ResourceInfo",
+                &rpc::ResourceInfo {
+                    resource_type: resource_type.clone(),
+                    resource_name: name.clone(),
+                    owner: "".into(),
+                    description: self.to_string(),
+                },
+            )),
+            Error::InvalidArgument(field_violations) => details.push(any(
+                "google.rpc.BadRequest",
+                &rpc::BadRequest {
+                    field_violations: field_violations.clone(),
+                },
+            )),
+            Error::FailedPrecondition {
+                subject,
+                description,
+            } => details.push(any(
+                "google.rpc.PreconditionFailure",
+                &rpc::PreconditionFailure {
+                    violations: vec![rpc::precondition_failure::Violation {
+                        r#type: "STATE".into(),
+                        subject: subject.clone(),
+                        description: description.clone(),
+                    }],
+                },
+            )),
+            _ => (),
+        }
+
+        details
+    }
+}
+
+fn any<M: Message>(type_name: &str, message: &M) -> prost_types::Any {
+    prost_types::Any {
+        type_url: format!("type.googleapis.com/{}", type_name),
+        value: message.encode_to_vec(),
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::NotFound {
+                resource_type,
+                name,
+            } => write!(f, "{} {} not found", resource_type, name),
+            Error::AlreadyExists {
+                resource_type,
+                name,
+            } => write!(f, "{} {} already exists", resource_type, name),
+            Error::InvalidArgument(field_violations) => {
+                let violations: Vec<String> = field_violations
+                    .iter()
+                    .map(|violation| format!("{}: {}", violation.field, violation.description))
+                    .collect();
+                write!(f, "invalid request, {}", violations.join(", "))
+            }
+            Error::FailedPrecondition {
+                subject,
+                description,
+            } => write!(f, "{}: {}", subject, description),
+            Error::Conflict(message) | Error::Unavailable(message) | Error::Internal(message) => {
+                write!(f, "{}", message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<Error> for Status {
+    fn from(error: Error) -> Self {
+        // internal details stay in the server log
+        let message = match &error {
+            Error::Internal(_) => {
+                println!("{:?}", error);
+                "Internal error".to_string()
+            }
+            _ => error.to_string(),
+        };
+
+        let status = rpc::Status {
+            code: error.code() as i32,
+            message: message.clone(),
+            details: error.details(),
+        };
+
+        Status::with_details(error.code(), message, status.encode_to_vec().into())
+    }
+}
@@ -0,0 +1,32 @@
+use crate::job;
+use mongodb::bson::DateTime;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+// Changes to jobs and executions, kept so clients can watch them
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Event {
+    // Grows with every event in the order the changes were committed,
+    // used to resume watching after the last seen one
+    pub cursor: i64,
+    pub event_type: EventType,
+    pub job_name: String,
+    // labels of the job when the event happened
+    #[serde(default)]
+    pub labels: HashMap<String, String>,
+    #[serde(default)]
+    pub execution_id: Option<String>,
+    #[serde(default)]
+    pub status: Option<job::Status>,
+    // events expire some time after it
+    pub created_at: DateTime,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub enum EventType {
+    JobCreated,
+    JobUpdated,
+    JobDisabled,
+    ExecutionStarted,
+    ExecutionFinished,
+}
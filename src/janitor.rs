@@ -0,0 +1,489 @@
+use crate::config::Config;
+use crate::db::{self, DBError, ExecutionFilter, DB};
+use crate::{job, storage};
+use chrono::{DateTime, Utc};
+use std::time::Duration;
+
+// Seconds between two runs when the config does not set it
+const DEFAULT_INTERVAL: u64 = 3600;
+
+// Executions deleted per job and run, the rest are left for the next run
+const BATCH: i64 = 1000;
+
+const DAY: i64 = 24 * 60 * 60;
+
+pub fn interval(config: &Config) -> Duration {
+    let seconds = config
+        .retention
+        .as_ref()
+        .and_then(|retention| retention.interval)
+        .unwrap_or(DEFAULT_INTERVAL);
+    Duration::from_secs(seconds)
+}
+
+// Deletes the executions, and their logs, that the retention of their job
+// or of the config no longer keeps. With dry_run they are only printed.
+pub async fn clean(config: &Config) -> Result<(), DBError> {
+    let db = db::get_db(config).await?;
+    clean_db(config, &*db).await
+}
+
+async fn clean_db(config: &Config, db: &(dyn DB + Send + Sync)) -> Result<(), DBError> {
+    let defaults = config.retention.clone().unwrap_or_default();
+
+    for name in db.execution_job_names().await? {
+        // executions of disabled jobs follow the config
+        let retention = db
+            .find_job(&name, true)
+            .await
+            .and_then(|job| job.retention)
+            .unwrap_or_default();
+        let keep_executions = retention
+            .keep_executions
+            .or(defaults.keep_executions)
+            .filter(|keep| *keep > 0);
+        let keep_days = retention
+            .keep_days
+            .or(defaults.keep_days)
+            .filter(|keep| *keep > 0);
+
+        let executions = expired(db, &name, keep_executions, keep_days).await?;
+        if executions.is_empty() {
+            continue;
+        }
+
+        if defaults.dry_run {
+            println!(
+                "retention: would delete {} executions of {}",
+                executions.len(),
+                name
+            );
+            for execution in &executions {
+                println!(
+                    "retention:   {} started {} log {}",
+                    execution.id,
+                    started(execution.start_time),
+                    execution.log
+                );
+            }
+            continue;
+        }
+
+        // an execution whose log could not be deleted is kept, so the
+        // log is not left behind and the next run tries again
+        let mut ids = vec![];
+        for execution in executions {
+            match delete_log(config, &execution).await {
+                Ok(()) => ids.extend(execution.object_id),
+                Err(error) => println!(
+                    "retention: could not delete log {} of {}: {}",
+                    execution.log, execution.id, error
+                ),
+            }
+        }
+
+        let deleted = db.delete_executions(&ids).await?;
+        println!("retention: deleted {} executions of {}", deleted, name);
+    }
+
+    Ok(())
+}
+
+// The executions past the most recent `keep_executions` or older than
+// `keep_days`, at most BATCH of each
+async fn expired(
+    db: &(dyn DB + Send + Sync),
+    name: &str,
+    keep_executions: Option<u32>,
+    keep_days: Option<u32>,
+) -> Result<Vec<job::Execution>, DBError> {
+    let mut executions: Vec<job::Execution> = vec![];
+
+    if let Some(keep) = keep_executions {
+        let filter = ExecutionFilter {
+            job_name: Some(name.into()),
+            skip: keep as u64,
+            limit: BATCH,
+            ..Default::default()
+        };
+        executions.extend(db.find_executions(&filter).await?);
+    }
+
+    if let Some(days) = keep_days {
+        let filter = ExecutionFilter {
+            job_name: Some(name.into()),
+            started_before: Some(Utc::now().timestamp() - days as i64 * DAY),
+            limit: BATCH,
+            ..Default::default()
+        };
+        for execution in db.find_executions(&filter).await? {
+            // executions saved before `id` existed all have an empty one
+            let found = executions
+                .iter()
+                .any(|expired| expired.object_id == execution.object_id);
+            if !found {
+                executions.push(execution);
+            }
+        }
+    }
+
+    // the worker still has to record how they end
+    executions.retain(|execution| execution.status != job::Status::RUNNING);
+    Ok(executions)
+}
+
+async fn delete_log(config: &Config, execution: &job::Execution) -> Result<(), storage::Error> {
+    // without a storage config no log was uploaded
+    let minio_config = match &config.minio {
+        Some(minio_config) if !execution.log.is_empty() => minio_config,
+        _ => return Ok(()),
+    };
+
+    storage::Client::connect(minio_config, storage::Objects::Logs)?
+        .delete(&execution.log)
+        .await
+}
+
+fn started(timestamp: i64) -> String {
+    match DateTime::<Utc>::from_timestamp(timestamp, 0) {
+        Some(time) => time.to_rfc3339(),
+        None => timestamp.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config;
+    use async_trait::async_trait;
+    use dcron_core::{event, heartbeat};
+    use mongodb::bson::oid::ObjectId;
+    use std::sync::Mutex;
+
+    // Only what the janitor uses
+    struct FakeDB {
+        jobs: Vec<job::Job>,
+        executions: Mutex<Vec<job::Execution>>,
+    }
+
+    #[async_trait]
+    impl DB for FakeDB {
+        async fn send_heartbeat(&self, _: &str) -> Result<(), DBError> {
+            unimplemented!()
+        }
+
+        async fn most_recent_heartbeat(&self) -> Result<Option<heartbeat::Heartbeat>, DBError> {
+            unimplemented!()
+        }
+
+        async fn find_job(&self, name: &str, active: bool) -> Option<job::Job> {
+            self.jobs
+                .iter()
+                .find(|job| job.name == name && job.active == active)
+                .cloned()
+        }
+
+        async fn find_all_active(&self) -> Result<Vec<job::Job>, DBError> {
+            unimplemented!()
+        }
+
+        async fn find_all_since(&self, _: bool, _: i64) -> Result<Vec<job::Job>, anyhow::Error> {
+            unimplemented!()
+        }
+
+        async fn disable_if_exist(&self, _: &str) -> Result<(), DBError> {
+            unimplemented!()
+        }
+
+        async fn upsert_job(&self, _: &job::Job, _: bool) -> Result<(), DBError> {
+            unimplemented!()
+        }
+
+        async fn update_job(&self, _: &job::Job, _: &job::Job) -> Result<(), DBError> {
+            unimplemented!()
+        }
+
+        async fn set_pause(&self, _: &str, _: Option<job::Pause>) -> Result<bool, DBError> {
+            unimplemented!()
+        }
+
+        async fn insert_execution(&self, _: &job::Execution) -> Result<(), DBError> {
+            unimplemented!()
+        }
+
+        async fn find_execution(&self, _: &str) -> Result<Option<job::Execution>, DBError> {
+            unimplemented!()
+        }
+
+        async fn find_latest_execution(&self, _: &str) -> Result<Option<job::Execution>, DBError> {
+            unimplemented!()
+        }
+
+        async fn update_execution(&self, _: &job::Execution) -> Result<(), DBError> {
+            unimplemented!()
+        }
+
+        async fn find_executions(
+            &self,
+            filter: &ExecutionFilter,
+        ) -> Result<Vec<job::Execution>, DBError> {
+            let mut executions: Vec<job::Execution> = self
+                .executions
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|execution| Some(&execution.job_name) == filter.job_name.as_ref())
+                .filter(|execution| match filter.started_before {
+                    Some(before) => execution.start_time < before,
+                    None => true,
+                })
+                .cloned()
+                .collect();
+            executions.sort_by(|a, b| (b.start_time, &b.id).cmp(&(a.start_time, &a.id)));
+
+            Ok(executions
+                .into_iter()
+                .skip(filter.skip as usize)
+                .take(filter.limit as usize)
+                .collect())
+        }
+
+        async fn execution_job_names(&self) -> Result<Vec<String>, DBError> {
+            let mut names: Vec<String> = self
+                .executions
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|execution| execution.job_name.clone())
+                .collect();
+            names.sort();
+            names.dedup();
+            Ok(names)
+        }
+
+        async fn delete_executions(&self, ids: &[ObjectId]) -> Result<u64, DBError> {
+            let mut executions = self.executions.lock().unwrap();
+            let before = executions.len();
+            executions.retain(|execution| !ids.contains(&execution.object_id.unwrap()));
+            Ok((before - executions.len()) as u64)
+        }
+
+        async fn find_events(&self, _: i64, _: i64) -> Result<Vec<event::Event>, DBError> {
+            unimplemented!()
+        }
+
+        async fn latest_event_cursor(&self) -> Result<i64, DBError> {
+            unimplemented!()
+        }
+    }
+
+    impl FakeDB {
+        fn new(jobs: Vec<job::Job>) -> Self {
+            FakeDB {
+                jobs,
+                executions: Mutex::new(vec![]),
+            }
+        }
+
+        // `count` finished executions of `name`, one a minute from `start_time`
+        fn add(&self, name: &str, count: usize, start_time: i64) {
+            let mut executions = self.executions.lock().unwrap();
+            for i in 0..count {
+                let id = format!("{}-{:05}", name, executions.len());
+                executions.push(execution(&id, name, start_time + i as i64 * 60));
+            }
+        }
+
+        fn count(&self, name: &str) -> usize {
+            let executions = self.executions.lock().unwrap();
+            executions
+                .iter()
+                .filter(|execution| execution.job_name == name)
+                .count()
+        }
+    }
+
+    fn execution(id: &str, name: &str, start_time: i64) -> job::Execution {
+        job::Execution {
+            object_id: Some(ObjectId::new()),
+            id: id.into(),
+            job_name: name.into(),
+            start_time,
+            log: "".into(),
+            status: job::Status::SUCCEEDED,
+            reason: "".into(),
+            worker: "".into(),
+            end_time: Some(start_time + 1),
+            exit_code: Some(0),
+            log_size: 0,
+            log_compression: Default::default(),
+        }
+    }
+
+    fn job(name: &str, retention: Option<job::Retention>) -> job::Job {
+        job::Job {
+            id: None,
+            name: name.into(),
+            time: "0 0 * * * *".into(),
+            job_type: 0,
+            script: "scripts/a".into(),
+            timeout: 0,
+            active: true,
+            updated_at: 0,
+            args: vec![],
+            env: Default::default(),
+            labels: Default::default(),
+            pause: None,
+            script_digest: None,
+            entrypoint: None,
+            retention,
+        }
+    }
+
+    fn config(keep_executions: Option<u32>, keep_days: Option<u32>, dry_run: bool) -> Config {
+        Config {
+            retention: Some(config::Retention {
+                keep_executions,
+                keep_days,
+                interval: None,
+                dry_run,
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn days_ago(days: i64) -> i64 {
+        Utc::now().timestamp() - days * DAY
+    }
+
+    #[tokio::test]
+    async fn keeps_the_most_recent_executions() {
+        let db = FakeDB::new(vec![job("a", None)]);
+        db.add("a", 8, days_ago(1));
+        let mut running = execution("a-running", "a", days_ago(2));
+        running.status = job::Status::RUNNING;
+        db.executions.lock().unwrap().push(running);
+
+        clean_db(&config(Some(3), None, false), &db).await.unwrap();
+
+        let executions = db.executions.lock().unwrap();
+        let mut ids: Vec<&str> = executions
+            .iter()
+            .map(|execution| execution.id.as_str())
+            .collect();
+        ids.sort();
+        // a running execution is left until it ends
+        assert_eq!(ids, vec!["a-00005", "a-00006", "a-00007", "a-running"]);
+    }
+
+    #[tokio::test]
+    async fn keeps_the_executions_of_the_last_days() {
+        let db = FakeDB::new(vec![]);
+        db.add("a", 4, days_ago(10));
+        db.add("a", 2, days_ago(1));
+
+        clean_db(&config(None, Some(5), false), &db).await.unwrap();
+
+        assert_eq!(db.count("a"), 2);
+        let executions = db.executions.lock().unwrap();
+        assert!(executions
+            .iter()
+            .all(|execution| execution.start_time > days_ago(5)));
+    }
+
+    #[tokio::test]
+    async fn either_limit_expires_an_execution() {
+        let db = FakeDB::new(vec![]);
+        db.add("a", 2, days_ago(10));
+        db.add("a", 5, days_ago(1));
+
+        clean_db(&config(Some(3), Some(5), false), &db)
+            .await
+            .unwrap();
+
+        assert_eq!(db.count("a"), 3);
+    }
+
+    #[tokio::test]
+    async fn the_retention_of_a_job_wins_over_the_config() {
+        let db = FakeDB::new(vec![
+            job(
+                "a",
+                Some(job::Retention {
+                    keep_executions: Some(2),
+                    keep_days: None,
+                }),
+            ),
+            job("b", None),
+            // only overrides the days, the count comes from the config
+            job(
+                "c",
+                Some(job::Retention {
+                    keep_executions: None,
+                    keep_days: Some(30),
+                }),
+            ),
+        ]);
+        db.add("a", 6, days_ago(1));
+        db.add("b", 6, days_ago(1));
+        db.add("c", 6, days_ago(20));
+
+        clean_db(&config(Some(4), Some(10), false), &db)
+            .await
+            .unwrap();
+
+        assert_eq!(db.count("a"), 2);
+        assert_eq!(db.count("b"), 4);
+        assert_eq!(db.count("c"), 4);
+    }
+
+    #[tokio::test]
+    async fn deletes_executions_saved_without_an_id() {
+        let db = FakeDB::new(vec![]);
+        db.add("a", 5, days_ago(10));
+        db.add("a", 2, days_ago(1));
+        for execution in db.executions.lock().unwrap().iter_mut() {
+            execution.id = "".into();
+        }
+
+        clean_db(&config(Some(4), Some(5), false), &db)
+            .await
+            .unwrap();
+        assert_eq!(db.count("a"), 2);
+
+        // nothing is left to expire on the next run
+        let expired = expired(&db, "a", Some(4), Some(5)).await.unwrap();
+        assert!(expired.is_empty());
+    }
+
+    #[tokio::test]
+    async fn dry_run_deletes_nothing() {
+        let db = FakeDB::new(vec![]);
+        db.add("a", 6, days_ago(10));
+
+        clean_db(&config(Some(1), Some(1), true), &db)
+            .await
+            .unwrap();
+
+        assert_eq!(db.count("a"), 6);
+    }
+
+    #[tokio::test]
+    async fn deletes_a_batch_per_run() {
+        let db = FakeDB::new(vec![]);
+        db.add("a", 2 * BATCH as usize + 10, days_ago(1));
+        let config = config(Some(10), None, false);
+
+        clean_db(&config, &db).await.unwrap();
+        assert_eq!(db.count("a"), BATCH as usize + 10);
+
+        clean_db(&config, &db).await.unwrap();
+        assert_eq!(db.count("a"), 10);
+
+        // the most recent are the ones left
+        let executions = db.executions.lock().unwrap();
+        assert!(executions
+            .iter()
+            .all(|execution| execution.id.as_str() >= "a-02000"));
+    }
+}
@@ -1,7 +1,13 @@
+use crate::config::Compression;
+use mongodb::bson::oid::ObjectId;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Job {
+    // of the stored version, never written so every version gets its own
+    #[serde(rename = "_id", default, skip_serializing)]
+    pub id: Option<ObjectId>,
     pub name: String,
     pub time: String,
     pub job_type: i32,
@@ -9,19 +15,86 @@ pub struct Job {
     pub timeout: i32,
     pub active: bool,
     pub updated_at: i64,
+    #[serde(default)]
+    pub args: Vec<String>,
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+    #[serde(default)]
+    pub labels: HashMap<String, String>,
+    #[serde(default)]
+    pub pause: Option<Pause>,
+    // hex SHA-256 of the script, None for jobs saved before it existed
+    #[serde(default)]
+    pub script_digest: Option<String>,
+    // the script inside the bundle at `script`, None for single scripts
+    #[serde(default)]
+    pub entrypoint: Option<String>,
+    // overrides the retention of the config
+    #[serde(default)]
+    pub retention: Option<Retention>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+// Unset fields fall back to the retention of the config
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct Retention {
+    pub keep_executions: Option<u32>,
+    pub keep_days: Option<u32>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Pause {
+    pub reason: String,
+    // unix timestamp, None means paused until resumed
+    pub until: Option<i64>,
+}
+
+impl Job {
+    pub fn is_paused(&self, now: i64) -> bool {
+        match &self.pause {
+            Some(Pause { until: Some(until), .. }) => now < *until,
+            Some(Pause { until: None, .. }) => true,
+            None => false,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Execution {
+    // of the stored document, the only id of executions saved before `id`
+    // existed
+    #[serde(rename = "_id", default, skip_serializing)]
+    pub object_id: Option<ObjectId>,
+    #[serde(default)]
+    pub id: String,
+    pub job_name: String,
     pub start_time: i64,
+    // object name of the uploaded log
     pub log: String,
     pub status: Status,
+    // Why the execution ended up in its status, e.g. the pause reason
+    #[serde(default)]
+    pub reason: String,
+    // url of the worker running it, used to reach the execution while it runs
+    #[serde(default)]
+    pub worker: String,
+    #[serde(default)]
+    pub end_time: Option<i64>,
+    #[serde(default)]
+    pub exit_code: Option<i32>,
+    // bytes written by the script, the stored log can be truncated
+    // and compressed
+    #[serde(default)]
+    pub log_size: u64,
+    #[serde(default)]
+    pub log_compression: Compression,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
 pub enum Status {
     RUNNING,
     TIMEOUT,
     FAILED,
     SUCCEEDED,
+    SKIPPED,
+    CANCELLED,
 }
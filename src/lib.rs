@@ -0,0 +1,10 @@
+// Modules shared by the server, client and scheduler binaries
+pub mod bundle;
+pub mod config;
+pub mod db;
+pub mod encryption;
+pub mod error;
+pub mod event;
+pub mod heartbeat;
+pub mod job;
+pub mod storage;
@@ -0,0 +1,464 @@
+use crate::dcron::{JobRequest, JobStatusResponse, Retention, ScriptType};
+use crate::storage::StoredScript;
+use anyhow::anyhow;
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// Label added to every job created by `apply`, only jobs with it are pruned
+pub const MANAGED_BY_LABEL: &str = "managed-by";
+pub const MANAGED_BY: &str = "dcron-apply";
+
+// A file with the jobs we want to exist, e.g. in TOML:
+//
+// [[jobs]]
+// name = "backup"
+// schedule = "0 0 3 * * *"
+// type = "python"
+// script = "scripts/backup.py"
+// timeout = 600
+// env = { BUCKET = "backups" }
+// labels = { team = "infra" }
+//
+// or the same keys under `jobs:` in YAML
+#[derive(Deserialize, Debug)]
+struct Manifest {
+    #[serde(default)]
+    jobs: Vec<JobSpec>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct JobSpec {
+    pub name: String,
+    pub schedule: String,
+    #[serde(rename = "type")]
+    pub job_type: String,
+    // relative to the manifest file, a directory is uploaded as a bundle
+    pub script: PathBuf,
+    // the script to run, relative to the bundle, for directories and archives
+    #[serde(default)]
+    pub entrypoint: Option<String>,
+    #[serde(default)]
+    pub timeout: i32,
+    #[serde(default)]
+    pub args: Vec<String>,
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+    #[serde(default)]
+    pub labels: HashMap<String, String>,
+    // e.g. retention = { keep_executions = 100, keep_days = 30 }
+    #[serde(default)]
+    pub retention: Option<RetentionSpec>,
+}
+
+// Unset fields fall back to the server config
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct RetentionSpec {
+    #[serde(default)]
+    pub keep_executions: u32,
+    #[serde(default)]
+    pub keep_days: u32,
+}
+
+impl JobSpec {
+    // The job the server should have once the script is stored
+    pub fn to_request(&self, script: &StoredScript) -> Result<JobRequest, anyhow::Error> {
+        let mut labels = self.labels.clone();
+        labels.insert(MANAGED_BY_LABEL.into(), MANAGED_BY.into());
+
+        Ok(JobRequest {
+            name: self.name.clone(),
+            time: self.schedule.clone(),
+            job_type: script_type(&self.job_type)? as i32,
+            location: script.location.clone(),
+            script_digest: script.digest.clone(),
+            entrypoint: self.entrypoint.clone().unwrap_or_default(),
+            retention: self.retention.as_ref().map(|retention| Retention {
+                keep_executions: retention.keep_executions,
+                keep_days: retention.keep_days,
+            }),
+            timeout: self.timeout,
+            update_if_exists: false,
+            args: self.args.clone(),
+            env: self.env.clone(),
+            labels,
+        })
+    }
+}
+
+// Reads a manifest file or every manifest inside a directory
+pub fn load(path: &Path) -> Result<Vec<JobSpec>, anyhow::Error> {
+    let mut files = vec![];
+    find_manifests(path, &mut files)?;
+
+    let mut jobs = vec![];
+    let mut names = HashSet::new();
+    for file in files {
+        for mut job in parse(&file)? {
+            if !names.insert(job.name.clone()) {
+                return Err(anyhow!("job {} is defined more than once", job.name));
+            }
+            script_type(&job.job_type)?;
+
+            let dir = file.parent().unwrap_or_else(|| Path::new("."));
+            job.script = dir.join(&job.script);
+            if job.script.is_dir() && job.entrypoint.is_none() {
+                return Err(anyhow!(
+                    "job {}: a directory script needs an entrypoint",
+                    job.name
+                ));
+            }
+            jobs.push(job);
+        }
+    }
+
+    Ok(jobs)
+}
+
+fn find_manifests(path: &Path, files: &mut Vec<PathBuf>) -> Result<(), anyhow::Error> {
+    if !path.is_dir() {
+        files.push(path.to_path_buf());
+        return Ok(());
+    }
+
+    let mut entries = vec![];
+    for entry in fs::read_dir(path)? {
+        entries.push(entry?.path());
+    }
+    // so jobs are always applied in the same order
+    entries.sort();
+
+    for entry in entries {
+        if entry.is_dir() {
+            find_manifests(&entry, files)?;
+        } else if is_manifest(&entry) {
+            files.push(entry);
+        }
+    }
+
+    Ok(())
+}
+
+fn is_manifest(path: &Path) -> bool {
+    matches!(
+        path.extension().and_then(|extension| extension.to_str()),
+        Some("toml") | Some("yaml") | Some("yml")
+    )
+}
+
+fn parse(file: &Path) -> Result<Vec<JobSpec>, anyhow::Error> {
+    let content = fs::read_to_string(file)
+        .map_err(|e| anyhow!("could not read {}: {}", file.display(), e))?;
+
+    let manifest: Manifest = match file.extension().and_then(|extension| extension.to_str()) {
+        Some("toml") => toml::from_str(&content)
+            .map_err(|e| anyhow!("invalid manifest {}: {}", file.display(), e))?,
+        Some("yaml") | Some("yml") => serde_yaml::from_str(&content)
+            .map_err(|e| anyhow!("invalid manifest {}: {}", file.display(), e))?,
+        _ => return Err(anyhow!("{} is not a .toml or .yaml file", file.display())),
+    };
+
+    Ok(manifest.jobs)
+}
+
+pub fn script_type(name: &str) -> Result<ScriptType, anyhow::Error> {
+    match name {
+        "python" => Ok(ScriptType::Python),
+        "ruby" => Ok(ScriptType::Ruby),
+        "shell" => Ok(ScriptType::Shell),
+        _ => Err(anyhow!("script type {} not supported", name)),
+    }
+}
+
+// What has to change on the server for a job of the manifests
+pub enum Change {
+    Create(JobSpec),
+    Update {
+        job: JobSpec,
+        // the current script, kept unless `location` changed
+        script: StoredScript,
+        fields: Vec<FieldDiff>,
+    },
+    Unchanged(String),
+    // a job created by `apply` that is no longer in the manifests
+    Disable(String),
+}
+
+pub struct FieldDiff {
+    // the update mask path
+    pub field: &'static str,
+    pub current: String,
+    pub wanted: String,
+}
+
+// The fields that differ between the job on the server and the one we want,
+// the script is compared by its digest instead of its location
+pub fn diff(wanted: &JobRequest, current: &JobStatusResponse) -> Vec<FieldDiff> {
+    let mut fields = vec![];
+    let mut compare = |field, current: String, wanted: String| {
+        if current != wanted {
+            fields.push(FieldDiff {
+                field,
+                current,
+                wanted,
+            });
+        }
+    };
+
+    compare("time", current.time.clone(), wanted.time.clone());
+    compare(
+        "job_type",
+        script_type_name(current.job_type),
+        script_type_name(wanted.job_type),
+    );
+    compare(
+        "entrypoint",
+        current.entrypoint.clone(),
+        wanted.entrypoint.clone(),
+    );
+    compare(
+        "timeout",
+        current.timeout.to_string(),
+        wanted.timeout.to_string(),
+    );
+    compare(
+        "args",
+        format!("{:?}", current.args),
+        format!("{:?}", wanted.args),
+    );
+    compare("env", show_map(&current.env), show_map(&wanted.env));
+    compare(
+        "labels",
+        show_map(&current.labels),
+        show_map(&wanted.labels),
+    );
+    compare(
+        "retention",
+        show_retention(&current.retention),
+        show_retention(&wanted.retention),
+    );
+
+    fields
+}
+
+pub fn script_type_name(job_type: i32) -> String {
+    match ScriptType::from_i32(job_type) {
+        Some(ScriptType::Python) => "python".into(),
+        Some(ScriptType::Ruby) => "ruby".into(),
+        Some(ScriptType::Shell) => "shell".into(),
+        None => job_type.to_string(),
+    }
+}
+
+// no retention and a retention of zeros both mean the server config
+fn show_retention(retention: &Option<Retention>) -> String {
+    let retention = retention.clone().unwrap_or_default();
+    format!(
+        "keep_executions={} keep_days={}",
+        retention.keep_executions, retention.keep_days
+    )
+}
+
+// sorted so the same map always prints the same
+fn show_map(map: &HashMap<String, String>) -> String {
+    let mut entries: Vec<String> = map
+        .iter()
+        .map(|(key, value)| format!("{}={}", key, value))
+        .collect();
+    entries.sort();
+    format!("{{{}}}", entries.join(", "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(dir: &Path, name: &str, content: &str) -> PathBuf {
+        let path = dir.join(name);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, content).unwrap();
+        path
+    }
+
+    fn job_status(job: &JobRequest) -> JobStatusResponse {
+        JobStatusResponse {
+            name: job.name.clone(),
+            time: job.time.clone(),
+            job_type: job.job_type,
+            location: job.location.clone(),
+            timeout: job.timeout,
+            args: job.args.clone(),
+            env: job.env.clone(),
+            labels: job.labels.clone(),
+            script_digest: job.script_digest.clone(),
+            entrypoint: job.entrypoint.clone(),
+            retention: job.retention.clone(),
+            ..Default::default()
+        }
+    }
+
+    fn request() -> JobRequest {
+        let job = JobSpec {
+            name: "backup".into(),
+            schedule: "0 0 3 * * *".into(),
+            job_type: "python".into(),
+            script: "backup.py".into(),
+            entrypoint: None,
+            timeout: 600,
+            args: vec!["--full".into()],
+            env: HashMap::from([("BUCKET".into(), "backups".into())]),
+            labels: HashMap::new(),
+            retention: None,
+        };
+        let script = StoredScript {
+            location: "scripts/sha256/abc".into(),
+            digest: "abc".into(),
+        };
+        job.to_request(&script).unwrap()
+    }
+
+    #[test]
+    fn parses_toml_and_yaml() {
+        let dir = tempfile::tempdir().unwrap();
+        let toml = write(
+            dir.path(),
+            "jobs.toml",
+            r#"
+[[jobs]]
+name = "backup"
+schedule = "0 0 3 * * *"
+type = "python"
+script = "backup.py"
+env = { BUCKET = "backups" }
+retention = { keep_days = 30 }
+"#,
+        );
+        let yaml = write(
+            dir.path(),
+            "jobs.yaml",
+            r#"
+jobs:
+  - name: report
+    schedule: "0 0 8 * * *"
+    type: shell
+    script: report.sh
+    args: ["--daily"]
+"#,
+        );
+
+        let jobs = parse(&toml).unwrap();
+        assert_eq!(jobs.len(), 1);
+        assert_eq!(jobs[0].name, "backup");
+        assert_eq!(jobs[0].env["BUCKET"], "backups");
+        assert_eq!(jobs[0].retention.as_ref().unwrap().keep_days, 30);
+        assert_eq!(jobs[0].retention.as_ref().unwrap().keep_executions, 0);
+
+        let jobs = parse(&yaml).unwrap();
+        assert_eq!(jobs[0].name, "report");
+        assert_eq!(jobs[0].job_type, "shell");
+        assert_eq!(jobs[0].args, vec!["--daily"]);
+    }
+
+    #[test]
+    fn parse_rejects_unknown_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = write(dir.path(), "jobs.json", "{}");
+        assert!(parse(&file).is_err());
+
+        let file = write(dir.path(), "jobs.toml", "[[jobs]]\nname = \"backup\"\n");
+        assert!(parse(&file).is_err());
+    }
+
+    #[test]
+    fn load_reads_a_directory_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        write(
+            dir.path(),
+            "b.toml",
+            "[[jobs]]\nname = \"b\"\nschedule = \"@daily\"\ntype = \"shell\"\nscript = \"b.sh\"\n",
+        );
+        write(
+            dir.path(),
+            "a/a.yml",
+            "jobs:\n  - name: a\n    schedule: \"@daily\"\n    type: ruby\n    script: a.rb\n",
+        );
+        write(dir.path(), "README.md", "not a manifest");
+
+        let jobs = load(dir.path()).unwrap();
+        let names: Vec<&str> = jobs.iter().map(|job| job.name.as_str()).collect();
+        assert_eq!(names, vec!["a", "b"]);
+        // scripts are relative to their manifest
+        assert_eq!(jobs[0].script, dir.path().join("a/a.rb"));
+        assert_eq!(jobs[1].script, dir.path().join("b.sh"));
+    }
+
+    #[test]
+    fn load_rejects_invalid_jobs() {
+        let job = "[[jobs]]\nname = \"a\"\nschedule = \"@daily\"\ntype = \"shell\"\nscript = \"a.sh\"\n";
+
+        let dir = tempfile::tempdir().unwrap();
+        write(dir.path(), "a.toml", job);
+        write(dir.path(), "b.toml", job);
+        assert!(load(dir.path()).is_err());
+
+        let dir = tempfile::tempdir().unwrap();
+        write(dir.path(), "a.toml", &job.replace("shell", "perl"));
+        assert!(load(dir.path()).is_err());
+
+        // a directory needs to say which script to run
+        let dir = tempfile::tempdir().unwrap();
+        write(dir.path(), "a.toml", &job.replace("a.sh", "bundle"));
+        write(dir.path(), "bundle/run.sh", "echo");
+        assert!(load(dir.path()).is_err());
+    }
+
+    #[test]
+    fn to_request_labels_the_job() {
+        let job = request();
+        assert_eq!(job.labels[MANAGED_BY_LABEL], MANAGED_BY);
+        assert_eq!(job.job_type, ScriptType::Python as i32);
+        assert_eq!(job.script_digest, "abc");
+    }
+
+    #[test]
+    fn diff_of_the_same_job_is_empty() {
+        let job = request();
+        assert!(diff(&job, &job_status(&job)).is_empty());
+    }
+
+    #[test]
+    fn diff_reports_changed_fields() {
+        let wanted = request();
+        let mut current = job_status(&wanted);
+        current.time = "0 0 4 * * *".into();
+        current.job_type = ScriptType::Shell as i32;
+        current.env.insert("REGION".into(), "eu".into());
+        // the location is compared by digest, not by diff
+        current.location = "scripts/sha256/def".into();
+
+        let fields = diff(&wanted, &current);
+        let names: Vec<&str> = fields.iter().map(|field| field.field).collect();
+        assert_eq!(names, vec!["time", "job_type", "env"]);
+        assert_eq!(fields[1].current, "shell");
+        assert_eq!(fields[1].wanted, "python");
+        assert_eq!(fields[2].current, "{BUCKET=backups, REGION=eu}");
+        assert_eq!(fields[2].wanted, "{BUCKET=backups}");
+    }
+
+    #[test]
+    fn no_retention_is_the_same_as_zeros() {
+        let wanted = request();
+        let mut current = job_status(&wanted);
+        current.retention = Some(Retention::default());
+        assert!(diff(&wanted, &current).is_empty());
+
+        current.retention = Some(Retention {
+            keep_executions: 10,
+            keep_days: 0,
+        });
+        let fields = diff(&wanted, &current);
+        assert_eq!(fields[0].field, "retention");
+        assert_eq!(fields[0].current, "keep_executions=10 keep_days=0");
+    }
+}
@@ -0,0 +1,671 @@
+use crate::dcron::{self, EventType, ExecutionStatus};
+use crate::manifest;
+use chrono::DateTime;
+use once_cell::sync::OnceCell;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Table,
+    Json,
+    Yaml,
+}
+
+static FORMAT: OnceCell<Format> = OnceCell::new();
+
+pub fn init(format: &str) {
+    let format = match format {
+        "json" => Format::Json,
+        "yaml" => Format::Yaml,
+        _ => Format::Table,
+    };
+    FORMAT.set(format).ok();
+}
+
+pub fn format() -> Format {
+    FORMAT.get().copied().unwrap_or(Format::Table)
+}
+
+// How a value is shown in the table output, the json and yaml output
+// use the serialized struct
+pub trait Row {
+    fn headers() -> Vec<&'static str>;
+    fn row(&self) -> Vec<String>;
+}
+
+// The fields below are the schema scripts rely on, only add to them
+
+#[derive(Serialize)]
+pub struct Job {
+    pub name: String,
+    pub schedule: String,
+    #[serde(rename = "type")]
+    pub job_type: String,
+    pub script: String,
+    pub timeout: i32,
+    pub args: Vec<String>,
+    pub env: BTreeMap<String, String>,
+    pub labels: BTreeMap<String, String>,
+    pub paused: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pause_reason: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub paused_until: Option<String>,
+}
+
+impl From<&dcron::JobStatusResponse> for Job {
+    fn from(job: &dcron::JobStatusResponse) -> Self {
+        Job {
+            name: job.name.clone(),
+            schedule: job.time.clone(),
+            job_type: manifest::script_type_name(job.job_type),
+            script: job.location.clone(),
+            timeout: job.timeout,
+            args: job.args.clone(),
+            env: sorted(&job.env),
+            labels: sorted(&job.labels),
+            paused: job.paused,
+            pause_reason: non_empty(&job.pause_reason),
+            paused_until: time(job.paused_until),
+        }
+    }
+}
+
+impl Row for Job {
+    fn headers() -> Vec<&'static str> {
+        vec!["NAME", "SCHEDULE", "TYPE", "TIMEOUT", "PAUSED", "LABELS"]
+    }
+
+    fn row(&self) -> Vec<String> {
+        vec![
+            self.name.clone(),
+            self.schedule.clone(),
+            self.job_type.clone(),
+            self.timeout.to_string(),
+            self.paused.to_string(),
+            pairs(&self.labels),
+        ]
+    }
+}
+
+// Everything `get` shows about a job
+#[derive(Serialize)]
+pub struct JobDetails {
+    #[serde(flatten)]
+    pub job: Job,
+    pub next_runs: Vec<String>,
+    // most recent first
+    pub executions: Vec<Execution>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub script: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct Execution {
+    pub id: String,
+    pub job: String,
+    pub status: String,
+    pub started_at: Option<String>,
+    pub finished_at: Option<String>,
+    pub duration_seconds: Option<u64>,
+    pub exit_code: Option<i32>,
+    pub worker: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    pub log: String,
+    pub log_size: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub log_compression: Option<String>,
+}
+
+impl From<&dcron::Execution> for Execution {
+    fn from(execution: &dcron::Execution) -> Self {
+        let finished = execution.finished_at != 0;
+        Execution {
+            id: execution.id.clone(),
+            job: execution.job.clone(),
+            status: execution_status(execution.status),
+            started_at: time(execution.executed_at as i64),
+            finished_at: time(execution.finished_at as i64),
+            duration_seconds: match finished {
+                true => Some(execution.finished_at.saturating_sub(execution.executed_at)),
+                false => None,
+            },
+            exit_code: execution.exit_code,
+            worker: execution.worker.clone(),
+            reason: non_empty(&execution.reason),
+            log: execution.log.clone(),
+            log_size: execution.log_size,
+            log_compression: non_empty(&execution.log_compression),
+        }
+    }
+}
+
+impl Row for Execution {
+    fn headers() -> Vec<&'static str> {
+        vec![
+            "ID", "JOB", "STATUS", "STARTED", "DURATION", "EXIT", "WORKER",
+        ]
+    }
+
+    fn row(&self) -> Vec<String> {
+        vec![
+            self.id.clone(),
+            self.job.clone(),
+            self.status.clone(),
+            self.started_at.clone().unwrap_or_default(),
+            match self.duration_seconds {
+                Some(duration) => format!("{}s", duration),
+                None => "".into(),
+            },
+            match self.exit_code {
+                Some(exit_code) => exit_code.to_string(),
+                None => "".into(),
+            },
+            self.worker.clone(),
+        ]
+    }
+}
+
+#[derive(Serialize)]
+pub struct ExecutionPage {
+    pub executions: Vec<Execution>,
+    // pass it as --page-token to get the next page
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_page_token: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct Event {
+    pub cursor: String,
+    #[serde(rename = "type")]
+    pub event_type: String,
+    pub job: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub execution_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<String>,
+    pub timestamp: Option<String>,
+    pub labels: BTreeMap<String, String>,
+}
+
+impl From<&dcron::Event> for Event {
+    fn from(event: &dcron::Event) -> Self {
+        let execution_id = non_empty(&event.execution_id);
+        Event {
+            cursor: event.cursor.clone(),
+            event_type: match EventType::from_i32(event.event_type) {
+                Some(EventType::JobCreated) => "job_created".into(),
+                Some(EventType::JobUpdated) => "job_updated".into(),
+                Some(EventType::JobDisabled) => "job_disabled".into(),
+                Some(EventType::ExecutionStarted) => "execution_started".into(),
+                Some(EventType::ExecutionFinished) => "execution_finished".into(),
+                None => event.event_type.to_string(),
+            },
+            job: event.job.clone(),
+            // the status is only set for execution events
+            status: execution_id
+                .as_ref()
+                .map(|_| execution_status(event.status)),
+            execution_id,
+            timestamp: time(event.timestamp as i64),
+            labels: sorted(&event.labels),
+        }
+    }
+}
+
+impl Row for Event {
+    fn headers() -> Vec<&'static str> {
+        vec!["CURSOR", "TYPE", "JOB", "EXECUTION", "STATUS", "TIME"]
+    }
+
+    fn row(&self) -> Vec<String> {
+        vec![
+            self.cursor.clone(),
+            self.event_type.clone(),
+            self.job.clone(),
+            self.execution_id.clone().unwrap_or_default(),
+            self.status.clone().unwrap_or_default(),
+            self.timestamp.clone().unwrap_or_default(),
+        ]
+    }
+}
+
+// The result of a command that changes something
+#[derive(Serialize)]
+pub struct Outcome {
+    pub name: String,
+    pub result: &'static str,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub changes: Vec<Change>,
+}
+
+#[derive(Serialize)]
+pub struct Change {
+    pub field: String,
+    pub current: String,
+    pub wanted: String,
+}
+
+impl Outcome {
+    pub fn new(name: &str, result: &'static str) -> Self {
+        Outcome {
+            name: name.into(),
+            result,
+            changes: vec![],
+        }
+    }
+}
+
+impl Row for Outcome {
+    fn headers() -> Vec<&'static str> {
+        vec!["NAME", "RESULT", "CHANGES"]
+    }
+
+    fn row(&self) -> Vec<String> {
+        let fields: Vec<&str> = self
+            .changes
+            .iter()
+            .map(|change| change.field.as_str())
+            .collect();
+        vec![self.name.clone(), self.result.into(), fields.join(", ")]
+    }
+}
+
+#[derive(Serialize)]
+pub struct LogLine {
+    pub line: String,
+}
+
+impl Row for LogLine {
+    fn headers() -> Vec<&'static str> {
+        vec!["LINE"]
+    }
+
+    fn row(&self) -> Vec<String> {
+        vec![self.line.clone()]
+    }
+}
+
+#[derive(Serialize)]
+pub struct SignedUrl {
+    pub url: String,
+    pub expires_at: Option<String>,
+}
+
+impl From<&dcron::SignedUrlResponse> for SignedUrl {
+    fn from(response: &dcron::SignedUrlResponse) -> Self {
+        SignedUrl {
+            url: response.url.clone(),
+            expires_at: time(response.expires_at),
+        }
+    }
+}
+
+impl Row for SignedUrl {
+    fn headers() -> Vec<&'static str> {
+        vec!["URL", "EXPIRES"]
+    }
+
+    fn row(&self) -> Vec<String> {
+        vec![self.url.clone(), self.expires_at.clone().unwrap_or_default()]
+    }
+}
+
+pub fn list<T: Serialize + Row>(items: &[T]) -> Result<(), Box<dyn std::error::Error>> {
+    match format() {
+        Format::Table => table(items),
+        Format::Json => println!("{}", serde_json::to_string_pretty(items)?),
+        Format::Yaml => print!("{}", serde_yaml::to_string(items)?),
+    }
+    Ok(())
+}
+
+pub fn one<T: Serialize + Row>(item: &T) -> Result<(), Box<dyn std::error::Error>> {
+    match format() {
+        Format::Table => table(std::slice::from_ref(item)),
+        _ => serialized(item)?,
+    }
+    Ok(())
+}
+
+// For values without a table form, printed as json with the table format
+pub fn serialized<T: Serialize>(item: &T) -> Result<(), Box<dyn std::error::Error>> {
+    match format() {
+        Format::Yaml => print!("{}", serde_yaml::to_string(item)?),
+        _ => println!("{}", serde_json::to_string_pretty(item)?),
+    }
+    Ok(())
+}
+
+// For values printed as they arrive: one json object per line, one yaml
+// document each, or tab separated rows as the widths are not known
+pub fn streamed<T: Serialize + Row>(
+    item: &T,
+    first: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    match format() {
+        Format::Table => {
+            if first {
+                println!("{}", T::headers().join("\t"));
+            }
+            println!("{}", item.row().join("\t"));
+        }
+        Format::Json => println!("{}", serde_json::to_string(item)?),
+        Format::Yaml => print!("{}", serde_yaml::to_string(item)?),
+    }
+    Ok(())
+}
+
+pub fn details(details: &JobDetails) -> Result<(), Box<dyn std::error::Error>> {
+    if format() != Format::Table {
+        return serialized(details);
+    }
+
+    let job = &details.job;
+    let mut fields = vec![
+        ("Name", job.name.clone()),
+        ("Schedule", job.schedule.clone()),
+        ("Type", job.job_type.clone()),
+        ("Script", job.script.clone()),
+        (
+            "Timeout",
+            match job.timeout {
+                0 => "none".into(),
+                timeout => format!("{}s", timeout),
+            },
+        ),
+    ];
+    if !job.args.is_empty() {
+        fields.push(("Args", job.args.join(" ")));
+    }
+    if !job.env.is_empty() {
+        fields.push(("Env", pairs(&job.env)));
+    }
+    if !job.labels.is_empty() {
+        fields.push(("Labels", pairs(&job.labels)));
+    }
+    if job.paused {
+        let mut paused = job.pause_reason.clone().unwrap_or_else(|| "yes".into());
+        if let Some(until) = &job.paused_until {
+            paused.push_str(&format!(" (until {})", until));
+        }
+        fields.push(("Paused", paused));
+    }
+    for (name, value) in fields {
+        println!("{:10}{}", format!("{}:", name), value);
+    }
+
+    println!("\nNext runs:");
+    for run in &details.next_runs {
+        println!("  {}", run);
+    }
+
+    println!("\nExecutions:");
+    table(&details.executions);
+
+    if let Some(script) = &details.script {
+        println!("\nScript:\n{}", script);
+    }
+
+    Ok(())
+}
+
+fn table<T: Row>(items: &[T]) {
+    let headers = T::headers();
+    let rows: Vec<Vec<String>> = items.iter().map(|item| item.row()).collect();
+
+    let mut widths: Vec<usize> = headers.iter().map(|header| header.len()).collect();
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    let line = |cells: Vec<String>| {
+        let cells: Vec<String> = cells
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{:width$}", cell, width = width))
+            .collect();
+        println!("{}", cells.join("  ").trim_end());
+    };
+
+    line(headers.iter().map(|header| header.to_string()).collect());
+    for row in rows {
+        line(row);
+    }
+}
+
+pub fn execution_status(status: i32) -> String {
+    match ExecutionStatus::from_i32(status) {
+        Some(ExecutionStatus::Running) => "running".into(),
+        Some(ExecutionStatus::Timeout) => "timeout".into(),
+        Some(ExecutionStatus::Failed) => "failed".into(),
+        Some(ExecutionStatus::Succeeded) => "succeeded".into(),
+        Some(ExecutionStatus::Skipped) => "skipped".into(),
+        Some(ExecutionStatus::Cancelled) => "cancelled".into(),
+        None => status.to_string(),
+    }
+}
+
+// RFC 3339, None for unset (zero) timestamps
+pub fn time(timestamp: i64) -> Option<String> {
+    if timestamp == 0 {
+        return None;
+    }
+    DateTime::from_timestamp(timestamp, 0).map(|time| time.to_rfc3339())
+}
+
+fn non_empty(value: &str) -> Option<String> {
+    match value.is_empty() {
+        true => None,
+        false => Some(value.into()),
+    }
+}
+
+// so the output does not change between calls
+fn sorted(map: &HashMap<String, String>) -> BTreeMap<String, String> {
+    map.iter()
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect()
+}
+
+fn pairs(map: &BTreeMap<String, String>) -> String {
+    let pairs: Vec<String> = map
+        .iter()
+        .map(|(key, value)| format!("{}={}", key, value))
+        .collect();
+    pairs.join(",")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn job() -> Job {
+        Job::from(&dcron::JobStatusResponse {
+            name: "backup".into(),
+            time: "0 0 3 * * *".into(),
+            job_type: dcron::ScriptType::Python as i32,
+            location: "scripts/sha256/abc".into(),
+            timeout: 600,
+            args: vec!["--full".into()],
+            env: HashMap::from([("BUCKET".into(), "backups".into())]),
+            labels: HashMap::from([("team".into(), "infra".into())]),
+            paused: true,
+            pause_reason: "maintenance".into(),
+            paused_until: 0,
+            ..Default::default()
+        })
+    }
+
+    fn execution() -> Execution {
+        Execution::from(&dcron::Execution {
+            id: "5f1d7c3e8a9b4c2d1e0f3a4b".into(),
+            job: "backup".into(),
+            status: ExecutionStatus::Failed as i32,
+            executed_at: 1_600_000_000,
+            finished_at: 1_600_000_042,
+            exit_code: Some(1),
+            worker: "http://[::1]:50051".into(),
+            log: "5f1d7c3e8a9b4c2d1e0f3a4b.log.gz".into(),
+            log_size: 2048,
+            log_compression: "gzip".into(),
+            ..Default::default()
+        })
+    }
+
+    // the keys of the yaml output, which are the same as the json ones
+    fn yaml_keys<T: Serialize>(item: &T) -> Vec<String> {
+        let value: serde_yaml::Value =
+            serde_yaml::from_str(&serde_yaml::to_string(item).unwrap()).unwrap();
+        value
+            .as_mapping()
+            .unwrap()
+            .iter()
+            .map(|(key, _)| key.as_str().unwrap().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn job_fields() {
+        let job = job();
+        assert_eq!(
+            serde_json::to_value(&job).unwrap(),
+            json!({
+                "name": "backup",
+                "schedule": "0 0 3 * * *",
+                "type": "python",
+                "script": "scripts/sha256/abc",
+                "timeout": 600,
+                "args": ["--full"],
+                "env": {"BUCKET": "backups"},
+                "labels": {"team": "infra"},
+                "paused": true,
+                "pause_reason": "maintenance",
+            })
+        );
+        assert_eq!(
+            yaml_keys(&job),
+            vec![
+                "name",
+                "schedule",
+                "type",
+                "script",
+                "timeout",
+                "args",
+                "env",
+                "labels",
+                "paused",
+                "pause_reason"
+            ]
+        );
+    }
+
+    #[test]
+    fn execution_fields() {
+        let execution = execution();
+        assert_eq!(
+            serde_json::to_value(&execution).unwrap(),
+            json!({
+                "id": "5f1d7c3e8a9b4c2d1e0f3a4b",
+                "job": "backup",
+                "status": "failed",
+                "started_at": "2020-09-13T12:26:40+00:00",
+                "finished_at": "2020-09-13T12:27:22+00:00",
+                "duration_seconds": 42,
+                "exit_code": 1,
+                "worker": "http://[::1]:50051",
+                "log": "5f1d7c3e8a9b4c2d1e0f3a4b.log.gz",
+                "log_size": 2048,
+                "log_compression": "gzip",
+            })
+        );
+        assert_eq!(
+            yaml_keys(&execution),
+            vec![
+                "id",
+                "job",
+                "status",
+                "started_at",
+                "finished_at",
+                "duration_seconds",
+                "exit_code",
+                "worker",
+                "log",
+                "log_size",
+                "log_compression"
+            ]
+        );
+    }
+
+    #[test]
+    fn unknown_values_are_null() {
+        let execution = Execution::from(&dcron::Execution {
+            id: "5f1d7c3e8a9b4c2d1e0f3a4b".into(),
+            status: ExecutionStatus::Running as i32,
+            executed_at: 1_600_000_000,
+            ..Default::default()
+        });
+
+        let value = serde_json::to_value(&execution).unwrap();
+        assert_eq!(value["status"], "running");
+        assert_eq!(value["finished_at"], serde_json::Value::Null);
+        assert_eq!(value["duration_seconds"], serde_json::Value::Null);
+        assert_eq!(value["exit_code"], serde_json::Value::Null);
+        assert!(value.get("reason").is_none());
+    }
+
+    #[test]
+    fn outcome_fields() {
+        assert_eq!(
+            serde_json::to_value(Outcome::new("backup", "created")).unwrap(),
+            json!({"name": "backup", "result": "created"})
+        );
+
+        let outcome = Outcome {
+            name: "backup".into(),
+            result: "updated",
+            changes: vec![Change {
+                field: "time".into(),
+                current: "0 0 3 * * *".into(),
+                wanted: "0 0 4 * * *".into(),
+            }],
+        };
+        assert_eq!(
+            serde_json::to_value(&outcome).unwrap(),
+            json!({
+                "name": "backup",
+                "result": "updated",
+                "changes": [
+                    {"field": "time", "current": "0 0 3 * * *", "wanted": "0 0 4 * * *"},
+                ],
+            })
+        );
+        assert_eq!(yaml_keys(&outcome), vec!["name", "result", "changes"]);
+        assert_eq!(outcome.row(), vec!["backup", "updated", "time"]);
+    }
+
+    #[test]
+    fn exit_codes() {
+        use tonic::{Code, Status};
+
+        let code = |code: Code| crate::exit_code(&Status::new(code, "failed"));
+        assert_eq!(crate::exit_code(&crate::Differences), 1);
+        assert_eq!(
+            crate::exit_code(&*Box::<dyn std::error::Error>::from("failed")),
+            2
+        );
+        assert_eq!(code(Code::Internal), 2);
+        assert_eq!(code(Code::NotFound), 3);
+        assert_eq!(code(Code::AlreadyExists), 4);
+        assert_eq!(code(Code::Aborted), 4);
+        assert_eq!(code(Code::InvalidArgument), 5);
+        assert_eq!(code(Code::FailedPrecondition), 5);
+        assert_eq!(code(Code::Unavailable), 6);
+        assert_eq!(code(Code::DeadlineExceeded), 6);
+    }
+}
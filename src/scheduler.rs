@@ -1,22 +1,28 @@
-use crate::config::Config;
+use crate::config::{Compression, Config};
 use chrono::Utc;
+use dcron::internal_client::InternalClient;
+use dcron::{ExecutionJobAck, ExecutionJobRequest};
 use std::collections::HashMap;
 use std::env;
 // job_scheduler crate https://docs.rs/job_scheduler/1.2.1/job_scheduler/
 use crate::job::Job;
-use anyhow;
 use closure::closure;
 use futures::executor::ThreadPool;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, RwLock};
 use std::thread;
 use std::time::Duration;
 use tokio::runtime;
 
-mod config;
-mod db;
-mod heartbeat;
-mod job;
-mod storage;
+use dcron_core::{config, db, job, storage};
+mod janitor;
+
+pub mod dcron {
+    tonic::include_proto!("dcron");
+}
+
+// Used to pick workers round-robin
+static NEXT_WORKER: AtomicUsize = AtomicUsize::new(0);
 
 // Maybe should use an Arc on the Scheduler itself
 pub struct Scheduler<'a> {
@@ -29,7 +35,8 @@ pub struct Scheduler<'a> {
     job_scheduler: job_scheduler::JobScheduler<'a>,
     config: Config,
 }
-#[derive(Clone, PartialEq, Eq)]
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Copy, PartialEq, Eq)]
 enum Role {
     LEADER,
     FOLLOWER,
@@ -45,7 +52,7 @@ impl Scheduler<'_> {
             config,
         }
     }
-    pub fn add_jobs(self: &mut Self, jobs: Vec<Job>) -> () {
+    pub fn add_jobs(&mut self, jobs: Vec<Job>) {
         for job in &jobs {
             self.jobs.insert(job.name.clone(), job.clone());
         }
@@ -58,7 +65,7 @@ impl Scheduler<'_> {
 // the job thread should request read lock, and send the job to a worker
 
 #[tokio::main]
-pub async fn main() -> () {
+pub async fn main() {
     // Gets all the jobs from the database and set jobs
     // Creates the new object
     // and finally runs the Scheduler
@@ -76,6 +83,8 @@ pub async fn main() -> () {
         run_health_checks(instance_role, health_check_config);
     });
 
+    tokio::spawn(run_janitor(config.clone(), role.clone()));
+
     // run an infinity loop
     // if this instance is a leader it will create a scheduler
     // and fetch updates for itself
@@ -83,6 +92,21 @@ pub async fn main() -> () {
     run_leader_scheduler(config.clone(), role).await;
 }
 
+// Applies the retention policies while this instance is the leader
+async fn run_janitor(config: Config, role: Arc<RwLock<Role>>) {
+    loop {
+        tokio::time::sleep(janitor::interval(&config)).await;
+
+        let leader = *role.read().unwrap() == Role::LEADER;
+        if !leader {
+            continue;
+        }
+        if let Err(error) = janitor::clean(&config).await {
+            println!("retention failed: {:?}", error);
+        }
+    }
+}
+
 fn server_name() -> String {
     //TODO get the IP:PORT
     "test".into()
@@ -145,9 +169,11 @@ async fn run_leader_scheduler(config: Config, role: Arc<RwLock<Role>>) -> ! {
         .expect("Could not get a Database connection");
 
     loop {
-        let role_reader = role.read().unwrap();
+        // copied out so the lock is not held while the scheduler runs,
+        // the health checks need it to step down
+        let current_role = *role.read().unwrap();
 
-        match *role_reader {
+        match current_role {
             Role::LEADER => println!("Still leader"),
             //TODO we probably can sleep after finding out we are a follower
             Role::FOLLOWER => continue, // Nothing to do, wait until we are the leader
@@ -178,15 +204,16 @@ fn schedule_all(scheduler: &mut Scheduler) -> Result<(), anyhow::Error> {
         schedule_job(job.clone(), &mut *scheduler)?;
     }
 
-    return Ok(());
+    Ok(())
 }
 
 fn schedule_job(job: job::Job, scheduler: &mut Scheduler) -> Result<(), anyhow::Error> {
     let job_name = job.name.clone();
+    let config = scheduler.config.clone();
     let job_id = scheduler.job_scheduler.add(job_scheduler::Job::new(
-        (&job.time).parse().unwrap(),
-        closure!(move job, || {
-            if let Err(err) = run_job(&job) {
+        job.time.parse().unwrap(),
+        closure!(move job, clone config, || {
+            if let Err(err) = run_job(&job, &config) {
                 println!("error while running job {:?}, {:?}", &job, err);
             }
         }),
@@ -196,7 +223,7 @@ fn schedule_job(job: job::Job, scheduler: &mut Scheduler) -> Result<(), anyhow::
     Ok(())
 }
 
-fn tick(scheduler: &mut Scheduler) -> () {
+fn tick(scheduler: &mut Scheduler) {
     scheduler.job_scheduler.tick();
 }
 
@@ -214,8 +241,8 @@ async fn fetch_job_updates<'a>(mut scheduler: Scheduler<'a>, role: Arc<RwLock<Ro
         reschedule_jobs_if_needed(&mut scheduler, last_updated_at);
         //This is terrible, but for now we also check here if we are still the leader
         // if not we should break and stop updating our scheduler
-        let role_reader = role.read().unwrap();
-        match *role_reader {
+        let current_role = *role.read().unwrap();
+        match current_role {
             Role::LEADER => println!("Still leader"),
             Role::FOLLOWER => break,
         };
@@ -257,7 +284,7 @@ async fn update_scheduler<'a>(
     let config = &scheduler.config;
     let last_updated_at = scheduler.last_updated_at;
 
-    let db = db::get_db(&config)
+    let db = db::get_db(config)
         .await
         .expect("Could not get a Database connection");
 
@@ -274,10 +301,92 @@ async fn update_scheduler<'a>(
     Ok(deleted_jobs)
 }
 
-fn run_job(job: &job::Job) -> Result<(), anyhow::Error> {
+fn run_job(job: &job::Job, config: &Config) -> Result<(), anyhow::Error> {
     // Acquires reader lock on job ids (not sure if we really need)
     // If standalone runs the script
     // Otherwise gets a worker IP and sends an execution request to it
-    println!("Would execute: {:?}", job);
+    let now = Utc::now().timestamp();
+    if job.is_paused(now) {
+        tokio::spawn(record_skipped(job.clone(), config.clone(), now));
+        return Ok(());
+    }
+
+    tokio::spawn(dispatch(job.clone(), config.clone()));
     Ok(())
 }
+
+// Sends the job to the next worker, if it refuses the job
+// the following ones are tried until one accepts it
+async fn dispatch(job: job::Job, config: Config) {
+    let workers = match &config.scheduler {
+        Some(scheduler) if !scheduler.workers.is_empty() => &scheduler.workers,
+        _ => {
+            println!("no workers configured, could not run {}", job.name);
+            return;
+        }
+    };
+
+    let request = ExecutionJobRequest {
+        job_type: job.job_type,
+        location: job.script.clone(),
+        name: job.name.clone(),
+        timeout: job.timeout,
+        execution_id: mongodb::bson::oid::ObjectId::new().to_hex(),
+        args: job.args.clone(),
+        env: job.env.clone(),
+        script_digest: job.script_digest.clone().unwrap_or_default(),
+        entrypoint: job.entrypoint.clone().unwrap_or_default(),
+    };
+
+    for _ in 0..workers.len() {
+        let worker = &workers[NEXT_WORKER.fetch_add(1, Ordering::Relaxed) % workers.len()];
+
+        let mut client = match InternalClient::connect(worker.clone()).await {
+            Ok(client) => client,
+            Err(err) => {
+                println!("could not connect to worker {}: {:?}", worker, err);
+                continue;
+            }
+        };
+
+        match client.execute_job(tonic::Request::new(request.clone())).await {
+            Ok(response) if response.get_ref().status == ExecutionJobAck::Accepted as i32 => {
+                return
+            }
+            Ok(_) => println!("worker {} rejected {}", worker, job.name),
+            Err(err) => println!("error while sending {} to {}: {:?}", job.name, worker, err),
+        }
+    }
+
+    println!("no worker accepted {}", job.name);
+}
+
+async fn record_skipped(job: job::Job, config: Config, now: i64) {
+    let reason = match &job.pause {
+        Some(pause) => pause.reason.clone(),
+        None => "".into(),
+    };
+    let execution = job::Execution {
+        object_id: None,
+        id: mongodb::bson::oid::ObjectId::new().to_hex(),
+        job_name: job.name,
+        start_time: now,
+        log: "".into(),
+        status: job::Status::SKIPPED,
+        reason,
+        worker: "".into(),
+        end_time: Some(now),
+        exit_code: None,
+        log_size: 0,
+        log_compression: Compression::None,
+    };
+
+    match db::get_db(&config).await {
+        Ok(db) => {
+            if let Err(db_error) = db.insert_execution(&execution).await {
+                println!("failed to record skipped execution: {:?}", db_error);
+            }
+        }
+        Err(db_error) => println!("failed to record skipped execution: {:?}", db_error),
+    }
+}
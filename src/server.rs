@@ -1,18 +1,28 @@
 use chrono::Utc;
 use db::DB;
+use dcron::internal_client::InternalClient;
+use dcron::internal_server::InternalServer;
 use dcron::public_server::{Public, PublicServer};
 use dcron::{
-    DisableJobRequest, DisableJobResponse, JobRequest, JobResponse, JobStatusRequest,
-    JobStatusResponse,
+    CancelExecutionRequest, CancelExecutionResponse, DisableJobRequest, DisableJobResponse,
+    EventType, ExecutionStatus, GetExecutionRequest, GetLogUrlRequest, GetScriptUrlRequest,
+    JobRequest, JobResponse, JobStatusRequest, JobStatusResponse, ListExecutionsRequest,
+    ListExecutionsResponse, ListJobsRequest, ListJobsResponse, PauseJobRequest, PauseJobResponse,
+    ResumeJobRequest, ResumeJobResponse, ScriptChunk, ScriptType, SignedUrlResponse,
+    TailExecutionRequest, UpdateJobRequest, UploadScriptResponse, WatchRequest,
 };
+use error::Error;
+use futures::{Stream, StreamExt};
 use once_cell::sync::OnceCell;
+use std::collections::VecDeque;
 use std::env;
-use tonic::{transport::Server, Code, Request, Response, Status};
-mod config;
-mod db;
-mod heartbeat;
-mod job;
-mod storage;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::time::Duration;
+use tonic::{transport::Server, Request, Response, Status, Streaming};
+use dcron_core::{bundle, config, db, error, event, job, storage};
+mod cache;
+mod worker;
 
 pub mod dcron {
     tonic::include_proto!("dcron"); // The string specified here must match the proto package name
@@ -20,6 +30,32 @@ pub mod dcron {
 
 static CONFIG: OnceCell<config::Config> = OnceCell::new();
 
+// Executions returned by GetJob, the rest is only available through ListExecutions
+const RECENT_EXECUTIONS: i64 = 10;
+const DEFAULT_PAGE_SIZE: i32 = 50;
+const MAX_PAGE_SIZE: i32 = 500;
+
+// How often a watch checks for new events when there are none
+const WATCH_POLL_INTERVAL: Duration = Duration::from_secs(1);
+const WATCH_BATCH: i64 = 100;
+
+// An uploaded script is buffered whole in memory before it is stored, as
+// its digest names the object, so every concurrent UploadScript can hold up
+// to 16 MiB. Larger scripts have to be uploaded to the storage directly.
+const MAX_SCRIPT_SIZE: usize = 16 * 1024 * 1024;
+
+// Lifetime of pre-signed urls in seconds, S3 does not accept more than 7 days
+const DEFAULT_URL_TTL: u32 = 15 * 60;
+const MAX_URL_TTL: u32 = 7 * 24 * 3600;
+
+struct WatchState {
+    db: Box<dyn DB + std::marker::Send + Sync>,
+    request: WatchRequest,
+    cursor: i64,
+    pending: VecDeque<event::Event>,
+    done: bool,
+}
+
 #[derive(Debug, Default)]
 pub struct DcronBasicServer {}
 
@@ -28,6 +64,7 @@ impl Public for DcronBasicServer {
     async fn new_job(&self, request: Request<JobRequest>) -> Result<Response<JobResponse>, Status> {
         let request = request.into_inner();
         let job = job::Job {
+            id: None,
             name: request.name,
             time: request.time,
             job_type: request.job_type,
@@ -35,129 +72,725 @@ impl Public for DcronBasicServer {
             script: request.location,
             active: true,
             updated_at: Utc::now().timestamp(),
+            args: request.args,
+            env: request.env,
+            labels: request.labels,
+            pause: None,
+            script_digest: non_empty(request.script_digest),
+            entrypoint: non_empty(request.entrypoint),
+            retention: to_retention(request.retention),
         };
 
-        let db = match get_db().await {
-            Ok(db) => db,
-            _ => {
-                return Err(Status::new(
-                    Code::Internal,
-                    "Could not connect to the database",
-                ))
-            }
+        validate(&job)?;
+
+        let db = get_db().await?;
+
+        db.upsert_job(&job, request.update_if_exists).await?;
+
+        let reply = dcron::JobResponse { name: job.name };
+
+        Ok(Response::new(reply))
+    }
+
+    async fn get_job(
+        &self,
+        request: Request<JobStatusRequest>,
+    ) -> Result<Response<JobStatusResponse>, Status> {
+        let request = request.into_inner();
+
+        let db = get_db().await?;
+
+        let job = match db.find_job(&request.name, true).await {
+            Some(job) => job,
+            None => return Err(Error::not_found("job", &request.name).into()),
         };
 
-        if request.update_if_exists {
-            match db.disable_if_exist(&job.name).await {
-                Err(error) => {
-                    print!("Erro while disabling job: {:?}", error);
-                    return Err(Status::new(Code::Internal, "Error while disabling old job"));
-                }
-                _ => (),
-            };
+        let filter = db::ExecutionFilter {
+            job_name: Some(job.name.clone()),
+            limit: RECENT_EXECUTIONS,
+            ..Default::default()
+        };
+        let executions = db.find_executions(&filter).await?;
+
+        let mut reply = job_status(job);
+        reply.executions = executions.into_iter().map(execution_message).collect();
+
+        Ok(Response::new(reply))
+    }
+
+    async fn disable_job(
+        &self,
+        request: Request<DisableJobRequest>,
+    ) -> Result<Response<DisableJobResponse>, Status> {
+        let request = request.into_inner();
+
+        let db = get_db().await?;
+
+        if db.find_job(&request.name, true).await.is_none() {
+            return Err(Error::not_found("job", &request.name).into());
         }
 
-        let result = db.insert_if_not_exist(&job).await;
+        db.disable_if_exist(&request.name).await?;
 
-        if let Err(error) = result {
-            println!("{:?}", error);
-            return Err(Status::new(
-                Code::Internal,
-                "Error while trying to save object",
-            ));
+        Ok(Response::new(DisableJobResponse {}))
+    }
+
+    async fn update_job(
+        &self,
+        request: Request<UpdateJobRequest>,
+    ) -> Result<Response<JobResponse>, Status> {
+        let request = request.into_inner();
+
+        let paths = match request.update_mask {
+            Some(mask) if !mask.paths.is_empty() => mask.paths,
+            _ => return Err(Error::invalid("update_mask", "must list at least one field").into()),
+        };
+        let fields = request.job.unwrap_or_default();
+
+        let db = get_db().await?;
+
+        let current = match db.find_job(&request.name, true).await {
+            Some(job) => job,
+            None => return Err(Error::not_found("job", &request.name).into()),
+        };
+        let mut job = current.clone();
+
+        for path in paths {
+            match path.as_str() {
+                "time" => job.time = fields.time.clone(),
+                "timeout" => job.timeout = fields.timeout,
+                "job_type" => job.job_type = fields.job_type,
+                "location" => {
+                    job.script = fields.location.clone();
+                    job.script_digest = non_empty(fields.script_digest.clone());
+                    job.entrypoint = non_empty(fields.entrypoint.clone());
+                }
+                "entrypoint" => job.entrypoint = non_empty(fields.entrypoint.clone()),
+                "retention" => job.retention = to_retention(fields.retention.clone()),
+                "args" => job.args = fields.args.clone(),
+                "env" => job.env = fields.env.clone(),
+                "labels" => job.labels = fields.labels.clone(),
+                _ => {
+                    return Err(Error::invalid(
+                        &format!("update_mask.paths.{}", path),
+                        "unknown field or it cannot be updated",
+                    )
+                    .into())
+                }
+            }
         }
+        job.updated_at = Utc::now().timestamp();
 
-        let reply = dcron::JobResponse {
-            name: job.name.into(),
-            error_code: 0,
-            error_message: "".into(),
+        validate(&job)?;
+
+        db.update_job(&job, &current).await?;
+
+        let reply = dcron::JobResponse { name: job.name };
+
+        Ok(Response::new(reply))
+    }
+
+    async fn list_jobs(
+        &self,
+        _request: Request<ListJobsRequest>,
+    ) -> Result<Response<ListJobsResponse>, Status> {
+        let db = get_db().await?;
+
+        let jobs = db.find_all_active().await?;
+
+        let reply = ListJobsResponse {
+            jobs: jobs.into_iter().map(job_status).collect(),
         };
 
         Ok(Response::new(reply))
     }
 
-    async fn get_job(
+    async fn pause_job(
         &self,
-        request: Request<JobStatusRequest>,
-    ) -> Result<Response<JobStatusResponse>, Status> {
+        request: Request<PauseJobRequest>,
+    ) -> Result<Response<PauseJobResponse>, Status> {
         let request = request.into_inner();
 
-        let db = match get_db().await {
-            Ok(db) => db,
-            _ => {
-                return Err(Status::new(
-                    Code::Internal,
-                    "Could not connect to the database",
+        if request.until != 0 && request.until <= Utc::now().timestamp() {
+            return Err(Error::invalid("until", "must be in the future").into());
+        }
+
+        let pause = job::Pause {
+            reason: request.reason,
+            until: match request.until {
+                0 => None,
+                until => Some(until),
+            },
+        };
+
+        set_pause(&request.name, Some(pause)).await?;
+
+        Ok(Response::new(PauseJobResponse {}))
+    }
+
+    async fn resume_job(
+        &self,
+        request: Request<ResumeJobRequest>,
+    ) -> Result<Response<ResumeJobResponse>, Status> {
+        let request = request.into_inner();
+
+        set_pause(&request.name, None).await?;
+
+        Ok(Response::new(ResumeJobResponse {}))
+    }
+
+    async fn cancel_execution(
+        &self,
+        request: Request<CancelExecutionRequest>,
+    ) -> Result<Response<CancelExecutionResponse>, Status> {
+        let request = request.into_inner();
+
+        let db = get_db().await?;
+
+        let execution = match db.find_execution(&request.execution_id).await? {
+            Some(execution) => execution,
+            None => return Err(Error::not_found("execution", &request.execution_id).into()),
+        };
+
+        if execution.status != job::Status::RUNNING {
+            return Err(Error::failed_precondition(
+                &format!("execution {}", execution.id),
+                &format!("is not running, status: {:?}", execution.status),
+            )
+            .into());
+        }
+
+        // the worker running it is the only one that can kill the process
+        let mut worker = match InternalClient::connect(execution.worker.clone()).await {
+            Ok(worker) => worker,
+            Err(error) => {
+                return Err(Error::Unavailable(format!(
+                    "Could not reach worker {}: {}",
+                    execution.worker, error
                 ))
+                .into())
+            }
+        };
+
+        worker.cancel_execution(Request::new(request)).await
+    }
+
+    type TailExecutionStream = worker::LogStream;
+
+    async fn tail_execution(
+        &self,
+        request: Request<TailExecutionRequest>,
+    ) -> Result<Response<Self::TailExecutionStream>, Status> {
+        let request = request.into_inner();
+
+        let db = get_db().await?;
+
+        let execution = if !request.execution_id.is_empty() {
+            db.find_execution(&request.execution_id).await?
+        } else {
+            db.find_latest_execution(&request.job).await?
+        };
+        let execution = match execution {
+            Some(execution) => execution,
+            None if request.execution_id.is_empty() => {
+                return Err(Error::not_found("execution of job", &request.job).into())
             }
+            None => return Err(Error::not_found("execution", &request.execution_id).into()),
         };
 
-        let job = db.find_job(&request.name, true).await;
+        if execution.status == job::Status::RUNNING {
+            if let Ok(mut worker) = InternalClient::connect(execution.worker.clone()).await {
+                let worker_request = TailExecutionRequest {
+                    execution_id: execution.id.clone(),
+                    job: "".into(),
+                    follow: request.follow,
+                };
+                if let Ok(lines) = worker.tail_execution(Request::new(worker_request)).await {
+                    return Ok(Response::new(Box::pin(lines.into_inner())));
+                }
+            }
+        }
+
+        // The execution is over (or finished while we were reaching the
+        // worker), so the log is read from the storage
+        let execution = match db.find_execution(&execution.id).await {
+            Ok(Some(execution)) => execution,
+            _ => execution,
+        };
+        let lines = stored_log(&execution).await?;
 
-        let job = match job {
-            Some(job) => job,
-            None => {
-                return Err(Status::new(
-                    Code::NotFound,
-                    "Error while trying to get object",
-                ))
+        Ok(Response::new(Box::pin(
+            futures::stream::iter(lines).map(worker::to_log_line),
+        )))
+    }
+
+    async fn get_execution(
+        &self,
+        request: Request<GetExecutionRequest>,
+    ) -> Result<Response<dcron::Execution>, Status> {
+        let request = request.into_inner();
+
+        let db = get_db().await?;
+
+        match db.find_execution(&request.id).await? {
+            Some(execution) => Ok(Response::new(execution_message(execution))),
+            None => Err(Error::not_found("execution", &request.id).into()),
+        }
+    }
+
+    async fn list_executions(
+        &self,
+        request: Request<ListExecutionsRequest>,
+    ) -> Result<Response<ListExecutionsResponse>, Status> {
+        let request = request.into_inner();
+
+        let mut statuses = vec![];
+        for (i, status) in request.statuses.iter().enumerate() {
+            match execution_status(*status) {
+                Some(status) => statuses.push(status),
+                None => {
+                    return Err(Error::invalid(
+                        &format!("statuses[{}]", i),
+                        &format!("unknown execution status {}", status),
+                    )
+                    .into())
+                }
             }
+        }
+
+        let after = if request.page_token.is_empty() {
+            None
+        } else {
+            match parse_page_token(&request.page_token) {
+                Some(after) => Some(after),
+                None => return Err(Error::invalid("page_token", "invalid page token").into()),
+            }
+        };
+
+        let page_size = match request.page_size {
+            size if size <= 0 => DEFAULT_PAGE_SIZE,
+            size => size.min(MAX_PAGE_SIZE),
+        };
+
+        let filter = db::ExecutionFilter {
+            job_name: match request.job.as_str() {
+                "" => None,
+                job => Some(job.into()),
+            },
+            statuses,
+            started_after: match request.started_after {
+                0 => None,
+                started_after => Some(started_after as i64),
+            },
+            started_before: match request.started_before {
+                0 => None,
+                started_before => Some(started_before as i64),
+            },
+            after,
+            skip: 0,
+            limit: page_size as i64,
         };
 
-        let reply = dcron::JobStatusResponse {
-            name: job.name,
-            timeout: job.timeout,
-            time: job.time,
-            error_code: 0,
-            job_type: job.job_type,
-            location: job.script,
-            executions: vec![], //TODO
+        let db = get_db().await?;
+
+        let executions = db.find_executions(&filter).await?;
+
+        // a full page may be followed by more executions
+        let next_page_token = match executions.last() {
+            Some(last) if executions.len() as i64 == filter.limit => page_token(last),
+            _ => "".into(),
+        };
+
+        let reply = ListExecutionsResponse {
+            executions: executions.into_iter().map(execution_message).collect(),
+            next_page_token,
         };
 
         Ok(Response::new(reply))
     }
 
-    async fn disable_job(
+    type WatchStream = Pin<Box<dyn Stream<Item = Result<dcron::Event, Status>> + Send>>;
+
+    async fn watch(
         &self,
-        request: Request<DisableJobRequest>,
-    ) -> Result<Response<DisableJobResponse>, Status> {
+        request: Request<WatchRequest>,
+    ) -> Result<Response<Self::WatchStream>, Status> {
         let request = request.into_inner();
 
-        let db = match get_db().await {
-            Ok(db) => db,
-            _ => {
-                return Err(Status::new(
-                    Code::Internal,
-                    "Could not connect to the database",
-                ))
+        let db = get_db().await?;
+
+        let cursor = if request.cursor.is_empty() {
+            db.latest_event_cursor().await?
+        } else {
+            match request.cursor.parse() {
+                Ok(cursor) => cursor,
+                Err(_) => return Err(Error::invalid("cursor", "invalid cursor").into()),
             }
         };
 
-        let job = db.find_job(&request.name, true).await;
+        let state = WatchState {
+            db,
+            request,
+            cursor,
+            pending: VecDeque::new(),
+            done: false,
+        };
 
-        let response = match job {
-            Some(_job) => db.disable_if_exist(&request.name),
-            None => {
-                return Err(Status::new(
-                    Code::NotFound,
-                    "Error while trying to get object",
-                ))
+        // Polls the events until the client goes away, which drops the stream
+        let events = futures::stream::unfold(state, |mut state| async move {
+            loop {
+                if state.done {
+                    return None;
+                }
+                if let Some(event) = state.pending.pop_front() {
+                    return Some((Ok(event_message(event)), state));
+                }
+
+                let events = match state.db.find_events(state.cursor, WATCH_BATCH).await {
+                    Ok(events) => events,
+                    Err(error) => {
+                        state.done = true;
+                        return Some((Err(error.into()), state));
+                    }
+                };
+
+                match events.last() {
+                    Some(last) => state.cursor = last.cursor,
+                    None => {
+                        tokio::time::sleep(WATCH_POLL_INTERVAL).await;
+                        continue;
+                    }
+                }
+
+                let request = &state.request;
+                let events: Vec<event::Event> = events
+                    .into_iter()
+                    .filter(|event| watched(request, event))
+                    .collect();
+                state.pending.extend(events);
+            }
+        });
+
+        Ok(Response::new(Box::pin(events)))
+    }
+
+    async fn upload_script(
+        &self,
+        request: Request<Streaming<ScriptChunk>>,
+    ) -> Result<Response<UploadScriptResponse>, Status> {
+        let mut chunks = request.into_inner();
+
+        let mut content = vec![];
+        while let Some(chunk) = chunks.message().await? {
+            if content.len() + chunk.content.len() > MAX_SCRIPT_SIZE {
+                return Err(Error::invalid(
+                    "content",
+                    &format!("scripts cannot be larger than {} bytes", MAX_SCRIPT_SIZE),
+                )
+                .into());
             }
+            content.extend_from_slice(&chunk.content);
+        }
+
+        let minio_config = match CONFIG.get().and_then(|config| config.minio.as_ref()) {
+            Some(minio_config) => minio_config,
+            None => return Err(Error::internal("No configuration for minio").into()),
         };
 
-        let reply = DisableJobResponse {
-            error_code: 0,
-            error_message: "".into(),
+        let script = storage::Client::connect(minio_config, storage::Objects::Scripts)?
+            .put_script(&content)
+            .await?;
+
+        Ok(Response::new(UploadScriptResponse {
+            location: script.location,
+            digest: script.digest,
+        }))
+    }
+
+    async fn get_log_url(
+        &self,
+        request: Request<GetLogUrlRequest>,
+    ) -> Result<Response<SignedUrlResponse>, Status> {
+        let request = request.into_inner();
+
+        let db = get_db().await?;
+
+        let execution = match db.find_execution(&request.execution_id).await? {
+            Some(execution) => execution,
+            None => return Err(Error::not_found("execution", &request.execution_id).into()),
         };
+        // the log is uploaded when the execution finishes
+        if execution.log.is_empty() {
+            return Err(
+                Error::failed_precondition("execution", "the execution has no log yet").into(),
+            );
+        }
 
-        match response.await {
-            Ok(_) => Ok(Response::new(reply)),
-            _ => Err(Status::new(
-                Code::Internal,
-                "Error while trying to update object",
-            )),
+        let url = signed_url(storage::Objects::Logs, &execution.log, request.ttl).await?;
+        Ok(Response::new(url))
+    }
+
+    async fn get_script_url(
+        &self,
+        request: Request<GetScriptUrlRequest>,
+    ) -> Result<Response<SignedUrlResponse>, Status> {
+        let request = request.into_inner();
+
+        let db = get_db().await?;
+
+        let job = match db.find_job(&request.job, true).await {
+            Some(job) => job,
+            None => return Err(Error::not_found("job", &request.job).into()),
+        };
+
+        let url = signed_url(storage::Objects::Scripts, &job.script, request.ttl).await?;
+        Ok(Response::new(url))
+    }
+}
+
+async fn signed_url(
+    objects: storage::Objects,
+    object_name: &str,
+    ttl: u32,
+) -> Result<SignedUrlResponse, Error> {
+    let ttl = match ttl {
+        0 => DEFAULT_URL_TTL,
+        ttl => ttl.min(MAX_URL_TTL),
+    };
+
+    let minio_config = match CONFIG.get().and_then(|config| config.minio.as_ref()) {
+        Some(minio_config) => minio_config,
+        None => return Err(Error::internal("No configuration for minio")),
+    };
+
+    let expires_at = Utc::now().timestamp() + ttl as i64;
+    let url = storage::Client::connect(minio_config, objects)?
+        .presign_get(object_name, ttl)
+        .await?;
+
+    Ok(SignedUrlResponse { url, expires_at })
+}
+
+fn watched(request: &WatchRequest, event: &event::Event) -> bool {
+    if !request.job.is_empty() && request.job != event.job_name {
+        return false;
+    }
+
+    request
+        .labels
+        .iter()
+        .all(|(key, value)| event.labels.get(key) == Some(value))
+}
+
+fn event_message(event: event::Event) -> dcron::Event {
+    let event_type = match event.event_type {
+        event::EventType::JobCreated => EventType::JobCreated,
+        event::EventType::JobUpdated => EventType::JobUpdated,
+        event::EventType::JobDisabled => EventType::JobDisabled,
+        event::EventType::ExecutionStarted => EventType::ExecutionStarted,
+        event::EventType::ExecutionFinished => EventType::ExecutionFinished,
+    };
+
+    dcron::Event {
+        cursor: event.cursor.to_string(),
+        event_type: event_type as i32,
+        job: event.job_name,
+        execution_id: event.execution_id.unwrap_or_default(),
+        status: event.status.map(status_message).unwrap_or(ExecutionStatus::Running) as i32,
+        timestamp: (event.created_at.timestamp_millis() / 1000) as u64,
+        labels: event.labels,
+    }
+}
+
+// Points at the last execution of a page, the next one starts after it
+fn page_token(execution: &job::Execution) -> String {
+    format!("{}:{}", execution.start_time, execution.id)
+}
+
+fn parse_page_token(token: &str) -> Option<(i64, String)> {
+    let (start_time, id) = token.split_once(':')?;
+    if id.is_empty() {
+        return None;
+    }
+    Some((start_time.parse().ok()?, id.into()))
+}
+
+fn execution_status(status: i32) -> Option<job::Status> {
+    match ExecutionStatus::from_i32(status)? {
+        ExecutionStatus::Running => Some(job::Status::RUNNING),
+        ExecutionStatus::Timeout => Some(job::Status::TIMEOUT),
+        ExecutionStatus::Failed => Some(job::Status::FAILED),
+        ExecutionStatus::Succeeded => Some(job::Status::SUCCEEDED),
+        ExecutionStatus::Skipped => Some(job::Status::SKIPPED),
+        ExecutionStatus::Cancelled => Some(job::Status::CANCELLED),
+    }
+}
+
+fn status_message(status: job::Status) -> ExecutionStatus {
+    match status {
+        job::Status::RUNNING => ExecutionStatus::Running,
+        job::Status::TIMEOUT => ExecutionStatus::Timeout,
+        job::Status::FAILED => ExecutionStatus::Failed,
+        job::Status::SUCCEEDED => ExecutionStatus::Succeeded,
+        job::Status::SKIPPED => ExecutionStatus::Skipped,
+        job::Status::CANCELLED => ExecutionStatus::Cancelled,
+    }
+}
+
+fn execution_message(execution: job::Execution) -> dcron::Execution {
+    dcron::Execution {
+        executed_at: execution.start_time as u64,
+        log: execution.log,
+        exit_code: execution.exit_code,
+        status: status_message(execution.status) as i32,
+        id: execution.id,
+        job: execution.job_name,
+        finished_at: execution.end_time.unwrap_or(0) as u64,
+        worker: execution.worker,
+        reason: execution.reason,
+        log_size: execution.log_size,
+        log_compression: compression_name(execution.log_compression).into(),
+    }
+}
+
+fn compression_name(compression: config::Compression) -> &'static str {
+    match compression {
+        config::Compression::None => "none",
+        config::Compression::Gzip => "gzip",
+        config::Compression::Zstd => "zstd",
+    }
+}
+
+async fn stored_log(execution: &job::Execution) -> Result<Vec<String>, Status> {
+    if execution.log.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let minio_config = match CONFIG.get().and_then(|config| config.minio.as_ref()) {
+        Some(minio_config) => minio_config,
+        None => return Err(Error::internal("No configuration for minio").into()),
+    };
+
+    let log = match storage::Client::connect(minio_config, storage::Objects::Logs)?
+        .get_log(&execution.log, execution.log_compression)
+        .await?
+    {
+        Some(log) => log,
+        None => return Ok(vec![]),
+    };
+
+    Ok(String::from_utf8_lossy(&log)
+        .split_inclusive('\n')
+        .map(String::from)
+        .collect())
+}
+
+async fn set_pause(name: &str, pause: Option<job::Pause>) -> Result<(), Status> {
+    let db = get_db().await?;
+
+    match db.set_pause(name, pause).await? {
+        true => Ok(()),
+        false => Err(Error::not_found("job", name).into()),
+    }
+}
+
+// Checks the fields a client can set before saving the job
+fn validate(job: &job::Job) -> Result<(), Error> {
+    let mut violations = vec![];
+
+    if job.name.is_empty() {
+        violations.push(("name", "must not be empty".to_string()));
+    }
+    if let Err(e) = job_scheduler::Schedule::from_str(&job.time) {
+        violations.push(("time", format!("invalid cron expression: {}", e)));
+    }
+    if job.timeout < 0 {
+        violations.push(("timeout", "must not be negative".to_string()));
+    }
+    if ScriptType::from_i32(job.job_type).is_none() {
+        violations.push(("job_type", format!("unknown script type {}", job.job_type)));
+    }
+    if job.script.is_empty() {
+        violations.push(("location", "must not be empty".to_string()));
+    }
+    if let Some(digest) = &job.script_digest {
+        if !storage::is_digest(digest) {
+            violations.push(("script_digest", "must be a hex SHA-256".to_string()));
+        }
+    }
+    if let Some(entrypoint) = &job.entrypoint {
+        if !bundle::is_entrypoint(entrypoint) {
+            violations.push((
+                "entrypoint",
+                "must be a relative path inside the bundle".to_string(),
+            ));
         }
     }
+
+    if violations.is_empty() {
+        return Ok(());
+    }
+
+    Err(Error::InvalidArgument(
+        violations
+            .into_iter()
+            .map(|(field, description)| error::FieldViolation {
+                field: field.into(),
+                description,
+            })
+            .collect(),
+    ))
+}
+
+fn job_status(job: job::Job) -> JobStatusResponse {
+    // an expired pause is reported as not paused
+    let paused = job.is_paused(Utc::now().timestamp());
+    let (pause_reason, paused_until) = match job.pause {
+        Some(pause) if paused => (pause.reason, pause.until.unwrap_or(0)),
+        _ => ("".into(), 0),
+    };
+
+    JobStatusResponse {
+        name: job.name,
+        timeout: job.timeout,
+        time: job.time,
+        job_type: job.job_type,
+        location: job.script,
+        executions: vec![], //TODO
+        args: job.args,
+        env: job.env,
+        labels: job.labels,
+        paused,
+        pause_reason,
+        paused_until,
+        script_digest: job.script_digest.unwrap_or_default(),
+        entrypoint: job.entrypoint.unwrap_or_default(),
+        retention: job.retention.map(|retention| dcron::Retention {
+            keep_executions: retention.keep_executions.unwrap_or_default(),
+            keep_days: retention.keep_days.unwrap_or_default(),
+        }),
+    }
+}
+
+fn non_empty(value: String) -> Option<String> {
+    match value.is_empty() {
+        true => None,
+        false => Some(value),
+    }
+}
+
+// Zeros fall back to the config, so a retention of zeros is no retention
+fn to_retention(retention: Option<dcron::Retention>) -> Option<job::Retention> {
+    let retention = retention?;
+    let retention = job::Retention {
+        keep_executions: Some(retention.keep_executions).filter(|keep| *keep > 0),
+        keep_days: Some(retention.keep_days).filter(|keep| *keep > 0),
+    };
+
+    match retention == job::Retention::default() {
+        true => None,
+        false => Some(retention),
+    }
 }
 
 async fn get_db() -> Result<Box<dyn DB + std::marker::Send + Sync>, db::DBError> {
@@ -165,7 +798,7 @@ async fn get_db() -> Result<Box<dyn DB + std::marker::Send + Sync>, db::DBError>
     let config = match CONFIG.get() {
         Some(config) => config,
         //TODO change this error
-        _ => return Err(db::DBError{message: "Could not get a config object".into()}),
+        _ => return Err(db::DBError::internal("Could not get a config object")),
     };
 
     db::get_db(config).await
@@ -181,12 +814,126 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let config = config::Config::from(&config_file);
     let config = config.expect("Error while trying to read configuration file");
 
+    // every server is also a worker the leader can send jobs to
+    let worker = worker::DcronWorker::new(config.clone());
+
+    if let Some(minio_config) = config.minio.as_ref().filter(|minio| minio.create_buckets) {
+        storage::create_buckets(minio_config).await?;
+    }
+
     CONFIG.set(config).expect("could not set configuration");
 
     Server::builder()
         .add_service(PublicServer::new(server))
+        .add_service(InternalServer::new(worker))
         .serve(addr)
         .await?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn valid_job() -> job::Job {
+        job::Job {
+            id: None,
+            name: "backup".into(),
+            time: "0 0 3 * * *".into(),
+            job_type: ScriptType::Python as i32,
+            script: "scripts/sha256/backup".into(),
+            timeout: 600,
+            active: true,
+            updated_at: 0,
+            args: vec![],
+            env: Default::default(),
+            labels: Default::default(),
+            pause: None,
+            script_digest: Some("a".repeat(64)),
+            entrypoint: Some("jobs/main.py".into()),
+            retention: None,
+        }
+    }
+
+    fn invalid_fields(job: &job::Job) -> Vec<String> {
+        match validate(job) {
+            Err(Error::InvalidArgument(violations)) => {
+                violations.into_iter().map(|violation| violation.field).collect()
+            }
+            Err(error) => panic!("unexpected error {:?}", error),
+            Ok(()) => vec![],
+        }
+    }
+
+    #[test]
+    fn validate_accepts_a_valid_job() {
+        assert!(validate(&valid_job()).is_ok());
+
+        let job = job::Job {
+            script_digest: None,
+            entrypoint: None,
+            ..valid_job()
+        };
+        assert!(validate(&job).is_ok());
+    }
+
+    #[test]
+    fn validate_reports_every_violation() {
+        let job = job::Job {
+            name: "".into(),
+            time: "every day".into(),
+            timeout: -1,
+            job_type: 99,
+            script: "".into(),
+            ..valid_job()
+        };
+
+        assert_eq!(
+            invalid_fields(&job),
+            vec!["name", "time", "timeout", "job_type", "location"]
+        );
+    }
+
+    #[test]
+    fn validate_checks_digest_and_entrypoint() {
+        let job = job::Job {
+            script_digest: Some("not-a-digest".into()),
+            entrypoint: Some("../main.py".into()),
+            ..valid_job()
+        };
+
+        assert_eq!(invalid_fields(&job), vec!["script_digest", "entrypoint"]);
+    }
+
+    #[test]
+    fn page_token_round_trip() {
+        let execution = job::Execution {
+            object_id: None,
+            id: "65f0c0ffee".into(),
+            job_name: "backup".into(),
+            start_time: 1700000000,
+            log: "".into(),
+            status: job::Status::SUCCEEDED,
+            reason: "".into(),
+            worker: "".into(),
+            end_time: None,
+            exit_code: Some(0),
+            log_size: 0,
+            log_compression: config::Compression::None,
+        };
+
+        assert_eq!(
+            parse_page_token(&page_token(&execution)),
+            Some((1700000000, "65f0c0ffee".to_string()))
+        );
+    }
+
+    #[test]
+    fn invalid_page_tokens() {
+        assert_eq!(parse_page_token(""), None);
+        assert_eq!(parse_page_token("1700000000"), None);
+        assert_eq!(parse_page_token("yesterday:65f0c0ffee"), None);
+        assert_eq!(parse_page_token("1700000000:"), None);
+    }
+}
@@ -1,107 +1,1087 @@
-use crate::config::Minio;
-use chrono;
+use crate::config::{Backend, Compression, Minio};
+use crate::encryption::{self, Keys, Metadata};
+use async_trait::async_trait;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use once_cell::sync::Lazy;
 use s3::bucket::Bucket;
 use s3::creds::Credentials;
 use s3::region::Region;
-use std::fs;
+use s3::BucketConfiguration;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Mutex;
 
-#[derive(Debug)]
-pub struct Error {
-    pub message: String,
+// Storage errors are part of the errors shared with the services
+pub use crate::error::Error;
+
+// Scripts are stored under their digest so the same content is only
+// uploaded once and a location always has the same content
+const SCRIPT_PREFIX: &str = "scripts/sha256/";
+
+// Files from this size on are uploaded to S3 in parts, so they never have
+// to be in memory at once
+const MULTIPART_THRESHOLD: u64 = s3::bucket::CHUNK_SIZE as u64;
+
+// Bucket used when the config does not name one
+pub const DEFAULT_BUCKET: &str = "rust-s3";
+
+// Subject of the errors of urls refused because of encryption
+pub const ENCRYPTED_URL: &str = "encryption";
+
+// Directory of the local backend with the metadata of its objects
+const METADATA_DIR: &str = ".metadata";
+
+// Objects of the memory backend, shared by every client of the process
+type MemoryObjects = HashMap<String, (Vec<u8>, Metadata)>;
+static MEMORY: Lazy<Mutex<MemoryObjects>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+pub struct StoredScript {
+    pub location: String,
+    pub digest: String,
 }
 
-pub struct Storage {
-    name: String,
-    region: Region,
-    credentials: Credentials,
-    bucket: String,
-    location_supported: bool,
+// hex sha256 of a script
+pub fn digest(content: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(content))
+}
+
+pub fn is_digest(value: &str) -> bool {
+    value.len() == 64 && value.chars().all(|c| matches!(c, '0'..='9' | 'a'..='f'))
+}
+
+// What is stored, each kind can have its own bucket
+#[derive(Clone, Copy, PartialEq)]
+pub enum Objects {
+    Scripts,
+    Logs,
+}
+
+// A bucket of one of the storages dcron can keep scripts and logs in.
+// Keys are the full object names, prefix included.
+#[async_trait]
+pub trait ObjectStore {
+    // Creates the bucket if it does not exist yet
+    async fn create_bucket(&self) -> Result<(), Error>;
+
+    async fn exists(&self, key: &str) -> Result<bool, Error>;
+
+    // The metadata of the object, None when it does not exist
+    async fn metadata(&self, key: &str) -> Result<Option<Metadata>, Error>;
+
+    // Stores the content under `key`, replacing what it had
+    async fn put(&self, key: &str, content: &[u8], metadata: &Metadata) -> Result<(), Error>;
+
+    // Same as put, without metadata, without reading the whole file into
+    // memory when the storage supports it
+    async fn put_file(&self, key: &str, file: &Path) -> Result<(), Error>;
+
+    // None when the object does not exist
+    async fn get(&self, key: &str) -> Result<Option<(Vec<u8>, Metadata)>, Error>;
+
+    // Writes the object to `file`, false when the object does not exist
+    async fn get_to_file(&self, key: &str, file: &Path) -> Result<bool, Error>;
+
+    // Deleting an object that does not exist is not an error
+    async fn delete(&self, key: &str) -> Result<(), Error>;
+
+    // A url to download the object without credentials for `expiry` seconds
+    fn presign_get(&self, key: &str, expiry: u32) -> Result<String, Error>;
 }
 
 pub struct Client {
-    pub storage: Storage,
+    store: Box<dyn ObjectStore + Send + Sync>,
+    // prepended to the object names, the names returned do not have it
+    prefix: String,
+    objects: Objects,
+    // objects are stored in plaintext without them
+    keys: Option<Keys>,
 }
 impl Client {
-    pub fn connect(minio_config: &Minio) -> Client {
-        let minio = Storage {
-            name: "minio".into(),
-            region: Region::Custom {
-                region: "".into(),
-                endpoint: (&minio_config.host).to_owned(),
+    pub fn connect(minio_config: &Minio, objects: Objects) -> Result<Client, Error> {
+        let bucket = bucket_name(minio_config, objects);
+
+        let store: Box<dyn ObjectStore + Send + Sync> = match minio_config.backend {
+            Backend::S3 => Box::new(S3Store::new(minio_config, &bucket)?),
+            Backend::Local => match &minio_config.path {
+                Some(path) => Box::new(LocalStore {
+                    root: Path::new(path).join(&bucket),
+                }),
+                None => return Err(Error::internal("The local storage needs a path")),
             },
-            credentials: Credentials {
-                access_key: Some((&minio_config.username).to_owned()),
-                secret_key: Some((&minio_config.password).to_owned()),
-                security_token: None,
-                session_token: None,
+            Backend::Memory => Box::new(MemoryStore { bucket }),
+        };
+        let keys = match &minio_config.encryption {
+            Some(encryption) => Some(Keys::load(encryption)?),
+            None => None,
+        };
+
+        Ok(Client {
+            store,
+            prefix: minio_config.prefix.clone().unwrap_or_default(),
+            objects,
+            keys,
+        })
+    }
+
+    // Uploads a file, returns the name it was stored with
+    pub async fn put(self, file: &Path, object_name: &str) -> Result<String, Error> {
+        if let Err(e) = tokio::fs::metadata(file).await {
+            return match e.kind() {
+                std::io::ErrorKind::NotFound => {
+                    Err(Error::not_found("file", &file.display().to_string()))
+                }
+                _ => Err(Error::Internal(format!("{}: {}", file.display(), e))),
+            };
+        }
+
+        let name = self.free_name(object_name).await?;
+        match self.keys {
+            // the whole content is needed to encrypt it
+            Some(_) => match tokio::fs::read(file).await {
+                Ok(content) => self.put_object(&name, &content).await?,
+                Err(e) => return Err(Error::Internal(format!("{}: {}", file.display(), e))),
             },
-            bucket: "rust-s3".to_string(),
-            location_supported: false,
+            None => self.store.put_file(&self.key(&name), file).await?,
+        }
+
+        Ok(name)
+    }
+
+    pub async fn put_script(self, content: &[u8]) -> Result<StoredScript, Error> {
+        let digest = digest(content);
+        let location = format!("{}{}", SCRIPT_PREFIX, digest);
+
+        // same digest, same content
+        if !self.store.exists(&self.key(&location)).await? {
+            self.put_object(&location, content).await?;
+        }
+
+        Ok(StoredScript { location, digest })
+    }
+
+    // None when the object does not exist, objects come back decrypted
+    // and logs decompressed
+    pub async fn get(self, object_name: &str) -> Result<Option<Vec<u8>>, Error> {
+        self.get_log(object_name, Compression::None).await
+    }
+
+    // Like get, with the compression recorded on the execution. The name
+    // only decides for the logs of executions that recorded none.
+    pub async fn get_log(
+        self,
+        object_name: &str,
+        compression: Compression,
+    ) -> Result<Option<Vec<u8>>, Error> {
+        let content = match self.store.get(&self.key(object_name)).await? {
+            Some((content, metadata)) => self.decrypt(object_name, content, &metadata)?,
+            None => return Ok(None),
+        };
+
+        // scripts are never compressed, bundles keep their .tar.gz as it is
+        let compression = match (self.objects, compression) {
+            (Objects::Scripts, _) => Compression::None,
+            (Objects::Logs, Compression::None) => Compression::of(object_name),
+            (Objects::Logs, compression) => compression,
         };
+        if compression == Compression::None {
+            return Ok(Some(content));
+        }
 
-        Client { storage: minio }
+        match tokio::task::spawn_blocking(move || decompress(&content, compression)).await {
+            Ok(Ok(content)) => Ok(Some(content)),
+            Ok(Err(e)) => Err(Error::Internal(format!("{}: {}", object_name, e))),
+            Err(e) => Err(Error::Internal(e.to_string())),
+        }
     }
 
-    pub async fn put(self: Self, file: &str, object_name: &str) -> Result<String, Error> {
-        let bucket = match self.bucket(){
-            Ok(b) => b,
-            Err(e) => return Err(e)
+    // Writes the object to `file` as it is received, false when the
+    // object does not exist
+    pub async fn get_to_file(self, object_name: &str, file: &Path) -> Result<bool, Error> {
+        if self.keys.is_none() {
+            // streamed as it is, so it has to be plaintext
+            match self.store.metadata(&self.key(object_name)).await? {
+                Some(metadata) if encryption::is_encrypted(&metadata) => {
+                    return Err(encrypted_without_keys())
+                }
+                Some(_) => return self.store.get_to_file(&self.key(object_name), file).await,
+                None => return Ok(false),
+            }
+        }
+
+        let content = match self.store.get(&self.key(object_name)).await? {
+            Some((content, metadata)) => self.decrypt(object_name, content, &metadata)?,
+            None => return Ok(false),
         };
+        match tokio::fs::write(file, content).await {
+            Ok(_) => Ok(true),
+            Err(e) => Err(Error::Internal(format!("{}: {}", file.display(), e))),
+        }
+    }
+
+    pub async fn delete(self, object_name: &str) -> Result<(), Error> {
+        self.store.delete(&self.key(object_name)).await
+    }
+
+    // The url would work but only give a 404
+    pub async fn presign_get(self, object_name: &str, expiry: u32) -> Result<String, Error> {
+        if self.keys.is_some() {
+            return Err(Error::failed_precondition(
+                ENCRYPTED_URL,
+                "encrypted objects cannot be read through urls",
+            ));
+        }
+        match self.store.metadata(&self.key(object_name)).await? {
+            Some(metadata) if encryption::is_encrypted(&metadata) => {
+                return Err(Error::failed_precondition(
+                    ENCRYPTED_URL,
+                    "the object is encrypted and cannot be read through a url",
+                ))
+            }
+            Some(_) => (),
+            None => return Err(Error::not_found("object", object_name)),
+        }
+        self.store.presign_get(&self.key(object_name), expiry)
+    }
+
+    // Encrypts the content when the config has keys
+    async fn put_object(&self, object_name: &str, content: &[u8]) -> Result<(), Error> {
+        let key = self.key(object_name);
+        match &self.keys {
+            Some(keys) => {
+                let (encrypted, metadata) = keys.encrypt(object_name, content)?;
+                self.store.put(&key, &encrypted, &metadata).await
+            }
+            None => self.store.put(&key, content, &Metadata::new()).await,
+        }
+    }
+
+    fn decrypt(
+        &self,
+        object_name: &str,
+        content: Vec<u8>,
+        metadata: &Metadata,
+    ) -> Result<Vec<u8>, Error> {
+        match &self.keys {
+            Some(keys) => keys.decrypt(object_name, content, metadata),
+            None if encryption::is_encrypted(metadata) => Err(encrypted_without_keys()),
+            None => Ok(content),
+        }
+    }
+
+    fn key(&self, object_name: &str) -> String {
+        format!("{}{}", self.prefix, object_name)
+    }
+
+    // The object name, with a timestamp prefix when an object already has it
+    async fn free_name(&self, object_name: &str) -> Result<String, Error> {
+        match self.store.exists(&self.key(object_name)).await? {
+            true => Ok(format!(
+                "{}_{}",
+                chrono::offset::Utc::now().timestamp(),
+                object_name
+            )),
+            false => Ok(object_name.into()),
+        }
+    }
+}
 
-        let code = match bucket.get_object(object_name).await  {
-            Ok((data, code)) => code,
-            Err(e) => return Err(Error{message: e.to_string()}),
+fn encrypted_without_keys() -> Error {
+    Error::failed_precondition(
+        "storage",
+        "the object is encrypted and the config has no encryption keys",
+    )
+}
+
+impl Compression {
+    // Compressed logs are stored with the extension, so they can be read
+    // back knowing only the object name
+    pub fn extension(self) -> &'static str {
+        match self {
+            Compression::None => "",
+            Compression::Gzip => ".gz",
+            Compression::Zstd => ".zst",
+        }
+    }
+
+    fn of(object_name: &str) -> Compression {
+        if object_name.ends_with(Compression::Gzip.extension()) {
+            Compression::Gzip
+        } else if object_name.ends_with(Compression::Zstd.extension()) {
+            Compression::Zstd
+        } else {
+            Compression::None
+        }
+    }
+}
+
+pub fn compress(content: &[u8], compression: Compression) -> Result<Vec<u8>, std::io::Error> {
+    match compression {
+        Compression::None => Ok(content.to_vec()),
+        Compression::Gzip => {
+            let mut encoder = GzEncoder::new(vec![], flate2::Compression::default());
+            encoder.write_all(content)?;
+            encoder.finish()
+        }
+        Compression::Zstd => zstd::encode_all(content, 0),
+    }
+}
+
+fn decompress(content: &[u8], compression: Compression) -> Result<Vec<u8>, std::io::Error> {
+    match compression {
+        Compression::None => Ok(content.to_vec()),
+        Compression::Gzip => {
+            let mut decompressed = vec![];
+            GzDecoder::new(content).read_to_end(&mut decompressed)?;
+            Ok(decompressed)
+        }
+        Compression::Zstd => zstd::decode_all(content),
+    }
+}
+
+// Creates the buckets of the config that do not exist yet
+pub async fn create_buckets(minio_config: &Minio) -> Result<(), Error> {
+    Client::connect(minio_config, Objects::Scripts)?
+        .store
+        .create_bucket()
+        .await?;
+    Client::connect(minio_config, Objects::Logs)?
+        .store
+        .create_bucket()
+        .await
+}
+
+fn bucket_name(minio_config: &Minio, objects: Objects) -> String {
+    let bucket = match objects {
+        Objects::Scripts => &minio_config.scripts_bucket,
+        Objects::Logs => &minio_config.logs_bucket,
+    };
+
+    match bucket.as_ref().or(minio_config.bucket.as_ref()) {
+        Some(bucket) => bucket.clone(),
+        None => DEFAULT_BUCKET.into(),
+    }
+}
+
+// Minio, AWS and any other storage with the S3 api, such as Ceph
+pub struct S3Store {
+    bucket: Bucket,
+    // needed to create the bucket
+    region: Region,
+    credentials: Credentials,
+}
+
+impl S3Store {
+    fn new(minio_config: &Minio, name: &str) -> Result<Self, Error> {
+        let region = Region::Custom {
+            region: minio_config.region.clone().unwrap_or_default(),
+            endpoint: minio_config.host.clone(),
+        };
+        let credentials = Credentials {
+            access_key: Some(minio_config.username.clone()),
+            secret_key: Some(minio_config.password.clone()),
+            security_token: None,
+            session_token: None,
+        };
+
+        let bucket = match minio_config.path_style.unwrap_or(true) {
+            true => Bucket::new_with_path_style(name, region.clone(), credentials.clone()),
+            false => Bucket::new(name, region.clone(), credentials.clone()),
+        };
+        let bucket = match bucket {
+            Ok(bucket) => bucket,
+            Err(e) => return Err(Error::Internal(e.to_string())),
         };
 
-        let name = match code {
-            200 => format!("{}_{}", chrono::offset::Utc::now().timestamp(), object_name).into(),
-            404 => object_name.into(),
-            _ => return Err(Error{message: "Could not verify if file exists already".into()}),
+        Ok(S3Store {
+            bucket,
+            region,
+            credentials,
+        })
+    }
+}
+
+#[async_trait]
+impl ObjectStore for S3Store {
+    async fn create_bucket(&self) -> Result<(), Error> {
+        let name = self.bucket.name();
+        let created = match self.bucket.is_path_style() {
+            true => {
+                Bucket::create_with_path_style(
+                    &name,
+                    self.region.clone(),
+                    self.credentials.clone(),
+                    BucketConfiguration::default(),
+                )
+                .await
+            }
+            false => {
+                Bucket::create(
+                    &name,
+                    self.region.clone(),
+                    self.credentials.clone(),
+                    BucketConfiguration::default(),
+                )
+                .await
+            }
         };
 
-        let content = fs::read_to_string(file).unwrap(); //TODO DO NOT UNWRAP!!!!
-        let result = bucket.put_object(&name, content.as_bytes()).await;
+        let code = match created {
+            Ok(response) => response.response_code,
+            Err(e) => return Err(Error::Unavailable(e.to_string())),
+        };
+        match code {
+            200 => println!("created bucket {}", name),
+            // BucketAlreadyOwnedByYou
+            409 => (),
+            _ => check(code, "create bucket", &name)?,
+        }
+
+        Ok(())
+    }
 
-        let code= match result {
+    async fn exists(&self, key: &str) -> Result<bool, Error> {
+        let code = match self.bucket.head_object(key).await {
             Ok((_, code)) => code,
-            Err(e) => return Err(Error{message: e.to_string()})
+            Err(e) => return Err(Error::Unavailable(e.to_string())),
+        };
+
+        match code {
+            404 => Ok(false),
+            _ => {
+                check(code, "check", key)?;
+                Ok(true)
+            }
+        }
+    }
+
+    async fn metadata(&self, key: &str) -> Result<Option<Metadata>, Error> {
+        let (head, code) = match self.bucket.head_object(key).await {
+            Ok(result) => result,
+            Err(e) => return Err(Error::Unavailable(e.to_string())),
         };
 
         match code {
-            200 => Ok(name),
-            _ => Err(Error{message: format!(
-                "Error while uploading file, http code = {}",
-                code
-            )}),
+            404 => Ok(None),
+            _ => {
+                check(code, "check", key)?;
+                Ok(Some(head.metadata.unwrap_or_default()))
+            }
         }
     }
 
-    fn bucket(self: Self) -> Result<Bucket, Error> {
-        match Bucket::new_with_path_style(
-            &self.storage.bucket,
-            self.storage.region,
-            self.storage.credentials,
-        ) {
-            Ok(b) => Ok(b),
-            Err(e) => Err(Error{message: e.to_string()})
+    async fn put(&self, key: &str, content: &[u8], metadata: &Metadata) -> Result<(), Error> {
+        // sent as x-amz-meta- headers
+        let mut bucket = self.bucket.clone();
+        for (name, value) in metadata {
+            bucket.add_header(&format!("x-amz-meta-{}", name), value);
         }
+
+        let code = match bucket.put_object(key, content).await {
+            Ok((_, code)) => code,
+            Err(e) => return Err(Error::Unavailable(e.to_string())),
+        };
+
+        check(code, "upload", key)
     }
 
-    pub async fn get(self: Self, object_name: &str) -> Result<Option<String>, Error> {
-        // todo check code
-        let object = match self.bucket() {
-            Ok(bucket) => bucket.get_object(object_name).await,
-            Err(e) => return Err(e),
+    async fn put_file(&self, key: &str, file: &Path) -> Result<(), Error> {
+        let size = match tokio::fs::metadata(file).await {
+            Ok(metadata) => metadata.len(),
+            Err(e) => return Err(Error::Internal(format!("{}: {}", file.display(), e))),
         };
+        if size < MULTIPART_THRESHOLD {
+            let content = match tokio::fs::read(file).await {
+                Ok(content) => content,
+                Err(e) => return Err(Error::Internal(format!("{}: {}", file.display(), e))),
+            };
+            return self.put(key, &content, &Metadata::new()).await;
+        }
+
+        let mut reader = match tokio::fs::File::open(file).await {
+            Ok(reader) => reader,
+            Err(e) => return Err(Error::Internal(format!("{}: {}", file.display(), e))),
+        };
+        let code = match self.bucket.put_object_stream(&mut reader, key).await {
+            Ok(code) => code,
+            Err(e) => return Err(Error::Unavailable(e.to_string())),
+        };
+        check(code, "upload", key)?;
+
+        // the parts' status codes are not checked by rust-s3, the size is
+        let (head, code) = match self.bucket.head_object(key).await {
+            Ok(result) => result,
+            Err(e) => return Err(Error::Unavailable(e.to_string())),
+        };
+        check(code, "upload", key)?;
+        let uploaded = head.content_length.unwrap_or(-1);
+        if uploaded != size as i64 {
+            return Err(Error::Internal(format!(
+                "could not upload {}, {} of {} bytes stored",
+                key, uploaded, size
+            )));
+        }
+
+        Ok(())
+    }
 
-        let (data, code) = match object {
+    async fn get(&self, key: &str) -> Result<Option<(Vec<u8>, Metadata)>, Error> {
+        let (data, code) = match self.bucket.get_object(key).await {
             Ok((data, code)) => (data, code),
-            Err(e) => return Err(Error{message: e.to_string()}),
+            Err(e) => return Err(Error::Unavailable(e.to_string())),
         };
+        match code {
+            404 => return Ok(None),
+            _ => check(code, "download", key)?,
+        }
+
+        // rust-s3 does not return the headers of a get
+        let (head, code) = match self.bucket.head_object(key).await {
+            Ok(result) => result,
+            Err(e) => return Err(Error::Unavailable(e.to_string())),
+        };
+        check(code, "download", key)?;
+
+        Ok(Some((data, head.metadata.unwrap_or_default())))
+    }
+
+    async fn get_to_file(&self, key: &str, file: &Path) -> Result<bool, Error> {
+        // rust-s3 only streams to a std::io::Write
+        let mut writer = match std::fs::File::create(file) {
+            Ok(writer) => writer,
+            Err(e) => return Err(Error::Internal(format!("{}: {}", file.display(), e))),
+        };
+        let result = self.bucket.get_object_stream(key, &mut writer).await;
+
+        let code = match result {
+            Ok(code) => code,
+            Err(e) => {
+                let _ = std::fs::remove_file(file);
+                return Err(Error::Unavailable(e.to_string()));
+            }
+        };
+        // the body of an error response is not the object
+        if !(200..300).contains(&code) {
+            let _ = std::fs::remove_file(file);
+        }
 
-        match std::str::from_utf8(&data) {
-            Ok(v) => Ok(Some(v.into())),
-            Err(e) => Err(Error{message: e.to_string()}),
+        match code {
+            404 => Ok(false),
+            _ => {
+                check(code, "download", key)?;
+                Ok(true)
+            }
         }
     }
+
+    async fn delete(&self, key: &str) -> Result<(), Error> {
+        let code = match self.bucket.delete_object(key).await {
+            Ok((_, code)) => code,
+            Err(e) => return Err(Error::Unavailable(e.to_string())),
+        };
+
+        match code {
+            404 => Ok(()),
+            _ => check(code, "delete", key),
+        }
+    }
+
+    fn presign_get(&self, key: &str, expiry: u32) -> Result<String, Error> {
+        match self.bucket.presign_get(key, expiry) {
+            Ok(url) => Ok(url),
+            Err(e) => Err(Error::Internal(format!("could not sign {}: {}", key, e))),
+        }
+    }
+}
+
+// rust-s3 only fails when the request could not be made, errors from the
+// storage come back as status codes
+fn check(code: u16, action: &str, key: &str) -> Result<(), Error> {
+    match code {
+        200..=299 => Ok(()),
+        404 => Err(Error::not_found("object", key)),
+        500..=599 => Err(Error::Unavailable(format!(
+            "could not {} {}, http code = {}",
+            action, key, code
+        ))),
+        _ => Err(Error::Internal(format!(
+            "could not {} {}, http code = {}",
+            action, key, code
+        ))),
+    }
+}
+
+// A directory per bucket, for a single box setup
+pub struct LocalStore {
+    root: PathBuf,
+}
+
+impl LocalStore {
+    // Keys come from job locations, they must not leave the bucket
+    fn path(&self, key: &str) -> Result<PathBuf, Error> {
+        let relative = Path::new(key);
+        let inside = relative
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)));
+        if key.is_empty() || !inside || relative.starts_with(METADATA_DIR) {
+            return Err(Error::invalid(
+                "location",
+                &format!("invalid object name {}", key),
+            ));
+        }
+
+        Ok(self.root.join(relative))
+    }
+
+    fn metadata_path(&self, key: &str) -> Result<PathBuf, Error> {
+        self.path(key)?;
+        Ok(self.root.join(METADATA_DIR).join(key))
+    }
+
+    // Metadata is kept as JSON, objects without it have no file
+    async fn put_metadata(&self, key: &str, metadata: &Metadata) -> Result<(), Error> {
+        let path = self.metadata_path(key)?;
+        if metadata.is_empty() {
+            return match tokio::fs::remove_file(&path).await {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                    Err(Error::Internal(format!("{}: {}", path.display(), e)))
+                }
+                _ => Ok(()),
+            };
+        }
+
+        LocalStore::parent_dir(&path).await?;
+        let content = match serde_json::to_vec(metadata) {
+            Ok(content) => content,
+            Err(e) => return Err(Error::Internal(e.to_string())),
+        };
+        match tokio::fs::write(&path, content).await {
+            Ok(_) => Ok(()),
+            Err(e) => Err(Error::Internal(format!("{}: {}", path.display(), e))),
+        }
+    }
+
+    async fn get_metadata(&self, key: &str) -> Result<Metadata, Error> {
+        let path = self.metadata_path(key)?;
+
+        let content = match tokio::fs::read(&path).await {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Metadata::new()),
+            Err(e) => return Err(Error::Internal(format!("{}: {}", path.display(), e))),
+        };
+        match serde_json::from_slice(&content) {
+            Ok(metadata) => Ok(metadata),
+            Err(e) => Err(Error::Internal(format!("{}: {}", path.display(), e))),
+        }
+    }
+
+    async fn parent_dir(path: &Path) -> Result<(), Error> {
+        if let Some(parent) = path.parent() {
+            if let Err(e) = tokio::fs::create_dir_all(parent).await {
+                return Err(Error::Internal(format!("{}: {}", parent.display(), e)));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl ObjectStore for LocalStore {
+    async fn create_bucket(&self) -> Result<(), Error> {
+        match tokio::fs::create_dir_all(&self.root).await {
+            Ok(_) => Ok(()),
+            Err(e) => Err(Error::Internal(format!("{}: {}", self.root.display(), e))),
+        }
+    }
+
+    async fn exists(&self, key: &str) -> Result<bool, Error> {
+        Ok(tokio::fs::metadata(self.path(key)?).await.is_ok())
+    }
+
+    async fn metadata(&self, key: &str) -> Result<Option<Metadata>, Error> {
+        match self.exists(key).await? {
+            true => Ok(Some(self.get_metadata(key).await?)),
+            false => Ok(None),
+        }
+    }
+
+    async fn put(&self, key: &str, content: &[u8], metadata: &Metadata) -> Result<(), Error> {
+        let path = self.path(key)?;
+        LocalStore::parent_dir(&path).await?;
+
+        if let Err(e) = tokio::fs::write(&path, content).await {
+            return Err(Error::Internal(format!("{}: {}", path.display(), e)));
+        }
+        self.put_metadata(key, metadata).await
+    }
+
+    async fn put_file(&self, key: &str, file: &Path) -> Result<(), Error> {
+        let path = self.path(key)?;
+        LocalStore::parent_dir(&path).await?;
+
+        if let Err(e) = tokio::fs::copy(file, &path).await {
+            return Err(Error::Internal(format!("{}: {}", file.display(), e)));
+        }
+        self.put_metadata(key, &Metadata::new()).await
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<(Vec<u8>, Metadata)>, Error> {
+        let path = self.path(key)?;
+
+        match tokio::fs::read(&path).await {
+            Ok(content) => Ok(Some((content, self.get_metadata(key).await?))),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(Error::Internal(format!("{}: {}", path.display(), e))),
+        }
+    }
+
+    async fn get_to_file(&self, key: &str, file: &Path) -> Result<bool, Error> {
+        let path = self.path(key)?;
+
+        match tokio::fs::copy(&path, file).await {
+            Ok(_) => Ok(true),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(Error::Internal(format!("{}: {}", path.display(), e))),
+        }
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), Error> {
+        let path = self.path(key)?;
+
+        match tokio::fs::remove_file(&path).await {
+            Ok(_) => (),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+            Err(e) => return Err(Error::Internal(format!("{}: {}", path.display(), e))),
+        }
+        self.put_metadata(key, &Metadata::new()).await
+    }
+
+    fn presign_get(&self, _key: &str, _expiry: u32) -> Result<String, Error> {
+        Err(Error::failed_precondition(
+            "storage",
+            "the local backend cannot sign urls",
+        ))
+    }
+}
+
+// Objects only live as long as the process, for tests and trying dcron out
+// with the server and the worker in the same process
+pub struct MemoryStore {
+    bucket: String,
+}
+
+impl MemoryStore {
+    fn name(&self, key: &str) -> String {
+        format!("{}/{}", self.bucket, key)
+    }
+}
+
+#[async_trait]
+impl ObjectStore for MemoryStore {
+    async fn create_bucket(&self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    async fn exists(&self, key: &str) -> Result<bool, Error> {
+        Ok(MEMORY.lock().unwrap().contains_key(&self.name(key)))
+    }
+
+    async fn metadata(&self, key: &str) -> Result<Option<Metadata>, Error> {
+        let objects = MEMORY.lock().unwrap();
+        Ok(objects
+            .get(&self.name(key))
+            .map(|(_, metadata)| metadata.clone()))
+    }
+
+    async fn put(&self, key: &str, content: &[u8], metadata: &Metadata) -> Result<(), Error> {
+        MEMORY
+            .lock()
+            .unwrap()
+            .insert(self.name(key), (content.to_vec(), metadata.clone()));
+        Ok(())
+    }
+
+    async fn put_file(&self, key: &str, file: &Path) -> Result<(), Error> {
+        match tokio::fs::read(file).await {
+            Ok(content) => self.put(key, &content, &Metadata::new()).await,
+            Err(e) => Err(Error::Internal(format!("{}: {}", file.display(), e))),
+        }
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<(Vec<u8>, Metadata)>, Error> {
+        Ok(MEMORY.lock().unwrap().get(&self.name(key)).cloned())
+    }
+
+    async fn get_to_file(&self, key: &str, file: &Path) -> Result<bool, Error> {
+        let content = match self.get(key).await? {
+            Some((content, _)) => content,
+            None => return Ok(false),
+        };
+
+        match tokio::fs::write(file, content).await {
+            Ok(_) => Ok(true),
+            Err(e) => Err(Error::Internal(format!("{}: {}", file.display(), e))),
+        }
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), Error> {
+        MEMORY.lock().unwrap().remove(&self.name(key));
+        Ok(())
+    }
+
+    fn presign_get(&self, _key: &str, _expiry: u32) -> Result<String, Error> {
+        Err(Error::failed_precondition(
+            "storage",
+            "the memory backend cannot sign urls",
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn memory_config(bucket: &str) -> Minio {
+        toml::from_str(&format!(
+            "backend = \"memory\"\nbucket = \"{}\"\nprefix = \"dcron/\"\n",
+            bucket
+        ))
+        .unwrap()
+    }
+
+    fn encrypted_config(bucket: &str) -> Minio {
+        let mut config = memory_config(bucket);
+        config.encryption = Some(
+            toml::from_str(&format!("key_id = \"a\"\nkey = \"{}\"\n", "ab".repeat(32))).unwrap(),
+        );
+        config
+    }
+
+    fn metadata(name: &str, value: &str) -> Metadata {
+        Metadata::from([(name.to_string(), value.to_string())])
+    }
+
+    #[test]
+    fn local_keys_stay_in_the_bucket() {
+        let store = LocalStore {
+            root: PathBuf::from("/var/lib/dcron/scripts"),
+        };
+
+        assert_eq!(
+            store.path("scripts/sha256/abc").unwrap(),
+            PathBuf::from("/var/lib/dcron/scripts/scripts/sha256/abc")
+        );
+        for key in [
+            "",
+            "../other/script",
+            "scripts/../../other",
+            "/etc/passwd",
+            "./script",
+            ".metadata/script",
+        ] {
+            assert!(store.path(key).is_err(), "{} was accepted", key);
+            assert!(store.metadata_path(key).is_err(), "{} was accepted", key);
+        }
+    }
+
+    #[tokio::test]
+    async fn local_store_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = LocalStore {
+            root: dir.path().join("bucket"),
+        };
+        store.create_bucket().await.unwrap();
+
+        assert_eq!(store.get("logs/a.log").await.unwrap(), None);
+        store
+            .put("logs/a.log", b"hello", &metadata("id", "1"))
+            .await
+            .unwrap();
+        assert!(store.exists("logs/a.log").await.unwrap());
+        assert_eq!(
+            store.get("logs/a.log").await.unwrap(),
+            Some((b"hello".to_vec(), metadata("id", "1")))
+        );
+
+        // replacing the object replaces its metadata
+        store
+            .put("logs/a.log", b"bye", &Metadata::new())
+            .await
+            .unwrap();
+        assert_eq!(
+            store.get("logs/a.log").await.unwrap(),
+            Some((b"bye".to_vec(), Metadata::new()))
+        );
+
+        let file = dir.path().join("a.log");
+        assert!(store.get_to_file("logs/a.log", &file).await.unwrap());
+        assert_eq!(std::fs::read(&file).unwrap(), b"bye");
+
+        store.delete("logs/a.log").await.unwrap();
+        store.delete("logs/a.log").await.unwrap();
+        assert!(!store.exists("logs/a.log").await.unwrap());
+        assert!(!store.get_to_file("logs/a.log", &file).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn local_store_does_not_write_outside_the_bucket() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = LocalStore {
+            root: dir.path().join("bucket"),
+        };
+
+        let result = store.put("../escaped", b"x", &Metadata::new()).await;
+        assert!(matches!(result, Err(Error::InvalidArgument(_))));
+        assert!(!dir.path().join("escaped").exists());
+
+        // the metadata of other objects cannot be overwritten either
+        let result = store.put(".metadata/a", b"{}", &Metadata::new()).await;
+        assert!(matches!(result, Err(Error::InvalidArgument(_))));
+    }
+
+    #[tokio::test]
+    async fn memory_buckets_are_separate() {
+        let scripts = MemoryStore {
+            bucket: "memory-buckets-scripts".into(),
+        };
+        let logs = MemoryStore {
+            bucket: "memory-buckets-logs".into(),
+        };
+
+        scripts
+            .put("a", b"script", &metadata("id", "1"))
+            .await
+            .unwrap();
+        assert!(scripts.exists("a").await.unwrap());
+        assert!(!logs.exists("a").await.unwrap());
+        assert_eq!(logs.get("a").await.unwrap(), None);
+        assert_eq!(
+            scripts.get("a").await.unwrap(),
+            Some((b"script".to_vec(), metadata("id", "1")))
+        );
+
+        scripts.delete("a").await.unwrap();
+        assert!(!scripts.exists("a").await.unwrap());
+        assert!(scripts.presign_get("a", 60).is_err());
+    }
+
+    #[tokio::test]
+    async fn scripts_are_stored_once_under_their_digest() {
+        let config = memory_config("stored-once");
+        let client = || Client::connect(&config, Objects::Scripts).unwrap();
+
+        let script = client().put_script(b"echo hello").await.unwrap();
+        assert_eq!(script.digest, digest(b"echo hello"));
+        assert_eq!(
+            script.location,
+            format!("{}{}", SCRIPT_PREFIX, script.digest)
+        );
+        // the prefix is part of the key, not of the location
+        assert!(MemoryStore {
+            bucket: "stored-once".into()
+        }
+        .exists(&format!("dcron/{}", script.location))
+        .await
+        .unwrap());
+
+        let again = client().put_script(b"echo hello").await.unwrap();
+        assert_eq!(again.location, script.location);
+        assert_eq!(
+            client().get(&script.location).await.unwrap(),
+            Some(b"echo hello".to_vec())
+        );
+        assert_eq!(client().get("missing").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn logs_are_read_back_decompressed() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("a.log.gz");
+        std::fs::write(&file, compress(b"line\n", Compression::Gzip).unwrap()).unwrap();
+
+        let config = memory_config("decompressed");
+        let client = || Client::connect(&config, Objects::Logs).unwrap();
+
+        let name = client().put(&file, "a.log.gz").await.unwrap();
+        assert_eq!(name, "a.log.gz");
+        assert_eq!(client().get(&name).await.unwrap(), Some(b"line\n".to_vec()));
+
+        // an existing name is not overwritten
+        let other = client().put(&file, "a.log.gz").await.unwrap();
+        assert_ne!(other, name);
+        assert!(other.ends_with("_a.log.gz"));
+
+        // the recorded compression wins over the name
+        let file = dir.path().join("b.log");
+        std::fs::write(&file, compress(b"line\n", Compression::Zstd).unwrap()).unwrap();
+        let name = client().put(&file, "b.log").await.unwrap();
+        assert_eq!(
+            client().get_log(&name, Compression::Zstd).await.unwrap(),
+            Some(b"line\n".to_vec())
+        );
+    }
+
+    #[tokio::test]
+    async fn encrypted_objects_are_read_back_decrypted() {
+        let config = encrypted_config("encrypted");
+        let client = || Client::connect(&config, Objects::Scripts).unwrap();
+
+        let script = client().put_script(b"echo hello").await.unwrap();
+        let (stored, metadata) = MemoryStore {
+            bucket: "encrypted".into(),
+        }
+        .get(&format!("dcron/{}", script.location))
+        .await
+        .unwrap()
+        .unwrap();
+        assert_ne!(stored, b"echo hello");
+        assert!(encryption::is_encrypted(&metadata));
+
+        assert_eq!(
+            client().get(&script.location).await.unwrap(),
+            Some(b"echo hello".to_vec())
+        );
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("script");
+        assert!(client().get_to_file(&script.location, &file).await.unwrap());
+        assert_eq!(std::fs::read(&file).unwrap(), b"echo hello");
+    }
+
+    #[tokio::test]
+    async fn encrypted_objects_need_the_keys() {
+        let encrypted = encrypted_config("without-keys");
+        let script = Client::connect(&encrypted, Objects::Scripts)
+            .unwrap()
+            .put_script(b"echo hello")
+            .await
+            .unwrap();
+
+        let config = memory_config("without-keys");
+        let client = || Client::connect(&config, Objects::Scripts).unwrap();
+
+        let result = client().get(&script.location).await;
+        assert!(matches!(result, Err(Error::FailedPrecondition { .. })));
+
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("script");
+        let result = client().get_to_file(&script.location, &file).await;
+        assert!(matches!(result, Err(Error::FailedPrecondition { .. })));
+        assert!(!file.exists());
+
+        let result = client().presign_get(&script.location, 60).await;
+        assert!(
+            matches!(result, Err(Error::FailedPrecondition { subject, .. }) if subject == ENCRYPTED_URL)
+        );
+
+        // nor with them
+        let result = Client::connect(&encrypted, Objects::Scripts)
+            .unwrap()
+            .presign_get(&script.location, 60)
+            .await;
+        assert!(
+            matches!(result, Err(Error::FailedPrecondition { subject, .. }) if subject == ENCRYPTED_URL)
+        );
+    }
 }
@@ -0,0 +1,771 @@
+use crate::dcron::internal_server::Internal;
+use crate::dcron::{
+    CancelExecutionRequest, CancelExecutionResponse, ExecutionJobAck, ExecutionJobRequest,
+    ExecutionJobResponse, LogLine, MetricsRequest, MetricsResponse, ScriptType,
+    TailExecutionRequest,
+};
+use crate::error::Error;
+use crate::cache::{self, ScriptCache, SharedCache};
+use crate::config::{Compression, Config, Minio};
+use crate::{bundle, db, job, storage};
+use chrono::Utc;
+use futures::{Stream, StreamExt};
+use std::collections::{HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::process::Stdio;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::process::Command;
+use tokio::sync::broadcast;
+use tonic::{Request, Response, Status};
+
+const DEFAULT_URL: &str = "http://[::1]:50051";
+
+// How many lines a slow tail can fall behind before it starts missing them
+const TAIL_CAPACITY: usize = 1024;
+
+// Used when the worker config does not set log_max_size
+const DEFAULT_LOG_MAX_SIZE: u64 = 64 * 1024 * 1024;
+
+// The output of the script is read at most this much at a time
+const CHUNK_SIZE: usize = 8 * 1024;
+// Longer lines are cut, so a script printing without newlines
+// cannot make the worker hold more than this of a line
+const MAX_LINE_SIZE: usize = 64 * 1024;
+
+struct RunningExecution {
+    // the script runs in its own process group so cancelling it
+    // also kills anything it spawned
+    pgid: Option<i32>,
+    cancelled: bool,
+    // what is kept of the log so far, replayed to new tails
+    output: Output,
+    // new lines are sent to the tails, the channel closes when
+    // the execution is removed
+    lines: broadcast::Sender<String>,
+}
+
+// Log of an execution. Past `max_size` bytes only its start and its end
+// are kept, with a line saying how much was left out in between.
+struct Output {
+    // 0 keeps everything
+    max_size: u64,
+    head: Vec<String>,
+    head_size: u64,
+    // once a line does not fit in the head, the next ones go to the tail
+    head_full: bool,
+    tail: VecDeque<String>,
+    tail_size: u64,
+    // bytes written by the script
+    size: u64,
+    truncated: u64,
+}
+
+impl Output {
+    fn new(max_size: u64) -> Self {
+        Output {
+            max_size,
+            head: vec![],
+            head_size: 0,
+            head_full: false,
+            tail: VecDeque::new(),
+            tail_size: 0,
+            size: 0,
+            truncated: 0,
+        }
+    }
+
+    // `written` is how many bytes the script wrote for the line,
+    // more than its length if it was cut
+    fn push(&mut self, line: String, written: u64) {
+        let len = line.len() as u64;
+        self.size += written;
+
+        let head_max = self.max_size / 2;
+        if self.max_size == 0 || (!self.head_full && self.head_size + len <= head_max) {
+            self.head_size += len;
+            self.head.push(line);
+            return;
+        }
+
+        self.head_full = true;
+        self.tail_size += len;
+        self.tail.push_back(line);
+        while self.tail_size > self.max_size - head_max {
+            match self.tail.pop_front() {
+                Some(dropped) => {
+                    self.tail_size -= dropped.len() as u64;
+                    self.truncated += dropped.len() as u64;
+                }
+                None => break,
+            }
+        }
+    }
+
+    fn lines(&self) -> Vec<String> {
+        let mut lines = self.head.clone();
+        if self.truncated > 0 {
+            lines.push(format!("[{} bytes truncated]\n", self.truncated));
+        }
+        lines.extend(self.tail.iter().cloned());
+        lines
+    }
+}
+
+// Executions running on this worker, keyed by execution id
+type Running = Arc<Mutex<HashMap<String, RunningExecution>>>;
+
+struct Outcome {
+    exit_code: Option<i32>,
+    timed_out: bool,
+    log: String,
+    log_size: u64,
+    log_compression: Compression,
+}
+
+pub type LogStream = Pin<Box<dyn Stream<Item = Result<LogLine, Status>> + Send>>;
+
+pub struct DcronWorker {
+    config: Config,
+    running: Running,
+    cache: SharedCache,
+}
+
+impl DcronWorker {
+    pub fn new(config: Config) -> Self {
+        let worker = config.worker.as_ref();
+        let cache_dir = match worker.and_then(|w| w.script_cache_dir.as_ref()) {
+            Some(cache_dir) => PathBuf::from(cache_dir),
+            None => work_dir(&config, "cache"),
+        };
+        let cache_size = match worker.and_then(|w| w.script_cache_size) {
+            Some(size) => size * 1024 * 1024,
+            None => cache::DEFAULT_SIZE,
+        };
+
+        Self {
+            running: Arc::new(Mutex::new(HashMap::new())),
+            cache: Arc::new(Mutex::new(ScriptCache::open(cache_dir, cache_size))),
+            config,
+        }
+    }
+
+    fn url(&self) -> String {
+        match &self.config.worker {
+            Some(worker) => worker.url.clone(),
+            None => DEFAULT_URL.into(),
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl Internal for DcronWorker {
+    async fn execute_job(
+        &self,
+        request: Request<ExecutionJobRequest>,
+    ) -> Result<Response<ExecutionJobResponse>, Status> {
+        let request = request.into_inner();
+        check_execution_id(&request.execution_id)?;
+
+        let execution = job::Execution {
+            object_id: None,
+            id: request.execution_id.clone(),
+            job_name: request.name.clone(),
+            start_time: Utc::now().timestamp(),
+            log: "".into(),
+            status: job::Status::RUNNING,
+            reason: "".into(),
+            worker: self.url(),
+            end_time: None,
+            exit_code: None,
+            log_size: 0,
+            log_compression: Compression::None,
+        };
+
+        let db = db::get_db(&self.config).await?;
+        db.insert_execution(&execution).await?;
+
+        self.running.lock().unwrap().insert(
+            execution.id.clone(),
+            RunningExecution {
+                pgid: None,
+                cancelled: false,
+                output: Output::new(log_max_size(&self.config)),
+                lines: broadcast::channel(TAIL_CAPACITY).0,
+            },
+        );
+
+        tokio::spawn(run(
+            self.config.clone(),
+            self.running.clone(),
+            self.cache.clone(),
+            request,
+            execution,
+        ));
+
+        Ok(Response::new(ExecutionJobResponse {
+            status: ExecutionJobAck::Accepted as i32,
+        }))
+    }
+
+    async fn cancel_execution(
+        &self,
+        request: Request<CancelExecutionRequest>,
+    ) -> Result<Response<CancelExecutionResponse>, Status> {
+        let request = request.into_inner();
+        let mut running = self.running.lock().unwrap();
+
+        match running.get_mut(&request.execution_id) {
+            Some(execution) => {
+                execution.cancelled = true;
+                // if the process was not started yet, `execute` kills it
+                // as soon as it has a pid
+                if let Some(pgid) = execution.pgid {
+                    kill_group(pgid);
+                }
+                Ok(Response::new(CancelExecutionResponse {}))
+            }
+            None => Err(Error::not_found("running execution", &request.execution_id).into()),
+        }
+    }
+
+    type TailExecutionStream = LogStream;
+
+    async fn tail_execution(
+        &self,
+        request: Request<TailExecutionRequest>,
+    ) -> Result<Response<Self::TailExecutionStream>, Status> {
+        let request = request.into_inner();
+        let running = self.running.lock().unwrap();
+
+        let execution = match running.get(&request.execution_id) {
+            Some(execution) => execution,
+            None => {
+                return Err(Error::not_found("running execution", &request.execution_id).into())
+            }
+        };
+
+        // subscribing while holding the lock guarantees no line is
+        // missed or sent twice between the snapshot and the channel
+        let output = futures::stream::iter(execution.output.lines());
+        if !request.follow {
+            return Ok(Response::new(Box::pin(output.map(to_log_line))));
+        }
+
+        let receiver = execution.lines.subscribe();
+        let new_lines = futures::stream::unfold(receiver, |mut receiver| async move {
+            match receiver.recv().await {
+                Ok(line) => Some((line, receiver)),
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    Some((format!("[{} lines skipped]\n", skipped), receiver))
+                }
+                Err(broadcast::error::RecvError::Closed) => None,
+            }
+        });
+
+        Ok(Response::new(Box::pin(
+            output.chain(new_lines).map(to_log_line),
+        )))
+    }
+
+    async fn get_metrics(
+        &self,
+        _request: Request<MetricsRequest>,
+    ) -> Result<Response<MetricsResponse>, Status> {
+        let metrics = self.cache.lock().unwrap().metrics();
+
+        Ok(Response::new(MetricsResponse {
+            script_cache_hits: metrics.hits,
+            script_cache_misses: metrics.misses,
+            script_cache_evictions: metrics.evictions,
+            script_cache_entries: metrics.entries,
+            script_cache_bytes: metrics.bytes,
+        }))
+    }
+}
+
+// the stream item type is defined by tonic
+#[allow(clippy::result_large_err)]
+pub fn to_log_line(line: String) -> Result<LogLine, Status> {
+    Ok(LogLine { line })
+}
+
+// Runs the execution to the end and records its final state
+async fn run(
+    config: Config,
+    running: Running,
+    cache: SharedCache,
+    request: ExecutionJobRequest,
+    mut execution: job::Execution,
+) {
+    let work_dir = work_dir(&config, &execution.id);
+    let result = execute(&config, &running, &cache, &request, &work_dir).await;
+
+    let cancelled = match running.lock().unwrap().get(&execution.id) {
+        Some(running_execution) => running_execution.cancelled,
+        None => false,
+    };
+
+    execution.end_time = Some(Utc::now().timestamp());
+    match result {
+        Ok(outcome) => {
+            execution.exit_code = outcome.exit_code;
+            execution.log = outcome.log;
+            execution.log_size = outcome.log_size;
+            execution.log_compression = outcome.log_compression;
+            execution.status = if cancelled {
+                job::Status::CANCELLED
+            } else if outcome.timed_out {
+                job::Status::TIMEOUT
+            } else if outcome.exit_code == Some(0) {
+                job::Status::SUCCEEDED
+            } else {
+                job::Status::FAILED
+            };
+        }
+        Err(error) => {
+            println!("execution {} failed: {:?}", execution.id, error);
+            execution.reason = error.to_string();
+            execution.status = if cancelled {
+                job::Status::CANCELLED
+            } else {
+                job::Status::FAILED
+            };
+        }
+    }
+
+    match db::get_db(&config).await {
+        Ok(db) => {
+            if let Err(db_error) = db.update_execution(&execution).await {
+                println!("failed to record execution: {:?}", db_error);
+            }
+        }
+        Err(db_error) => println!("failed to record execution: {:?}", db_error),
+    }
+
+    // only removed once the final state is saved, so a tail that
+    // finds the execution gone can read the log from the storage
+    running.lock().unwrap().remove(&execution.id);
+
+    if let Err(error) = tokio::fs::remove_dir_all(&work_dir).await {
+        println!("could not clean up {:?}: {:?}", work_dir, error);
+    }
+}
+
+async fn execute(
+    config: &Config,
+    running: &Running,
+    cache: &SharedCache,
+    request: &ExecutionJobRequest,
+    work_dir: &Path,
+) -> Result<Outcome, anyhow::Error> {
+    let minio_config = match &config.minio {
+        Some(minio_config) => minio_config,
+        None => return Err(anyhow::anyhow!("No configuration for minio")),
+    };
+
+    tokio::fs::create_dir_all(work_dir).await?;
+
+    let download_path = work_dir.join("script");
+    fetch_script(minio_config, cache, request, &download_path).await?;
+
+    // bundles run from their root so the entrypoint finds the other files
+    let (script_path, current_dir) = match request.entrypoint.is_empty() {
+        true => (download_path, work_dir.to_path_buf()),
+        false => {
+            let bundle_dir = work_dir.join("bundle");
+            let archive = download_path.clone();
+            let dir = bundle_dir.clone();
+            tokio::task::spawn_blocking(move || bundle::unpack(&archive, &dir)).await??;
+
+            let script_path = bundle_dir.join(&request.entrypoint);
+            if !bundle::is_entrypoint(&request.entrypoint) || !script_path.is_file() {
+                return Err(anyhow::anyhow!(
+                    "Entrypoint {} not found in bundle {}",
+                    request.entrypoint,
+                    request.location
+                ));
+            }
+            (script_path, bundle_dir)
+        }
+    };
+
+    let mut child = Command::new(interpreter(request.job_type)?)
+        .arg(&script_path)
+        .args(&request.args)
+        .envs(&request.env)
+        .current_dir(&current_dir)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .process_group(0)
+        .kill_on_drop(true)
+        .spawn()?;
+
+    let pgid = child.id().map(|pid| pid as i32);
+    if let Some(running_execution) = running.lock().unwrap().get_mut(&request.execution_id) {
+        running_execution.pgid = pgid;
+        if running_execution.cancelled {
+            if let Some(pgid) = pgid {
+                kill_group(pgid);
+            }
+        }
+    }
+
+    let id = request.execution_id.clone();
+    let stdout = tokio::spawn(collect(child.stdout.take(), running.clone(), id.clone()));
+    let stderr = tokio::spawn(collect(child.stderr.take(), running.clone(), id.clone()));
+
+    let mut timed_out = false;
+    let status = if request.timeout > 0 {
+        let timeout = Duration::from_secs(request.timeout as u64);
+        match tokio::time::timeout(timeout, child.wait()).await {
+            Ok(status) => status?,
+            Err(_) => {
+                timed_out = true;
+                if let Some(pgid) = pgid {
+                    kill_group(pgid);
+                }
+                child.wait().await?
+            }
+        }
+    } else {
+        child.wait().await?
+    };
+
+    // the pipes are closed once the process group is gone
+    let _ = stdout.await;
+    let _ = stderr.await;
+
+    let (log, log_size) = match running.lock().unwrap().get(&id) {
+        Some(running_execution) => (
+            running_execution.output.lines().concat(),
+            running_execution.output.size,
+        ),
+        None => ("".into(), 0),
+    };
+
+    let compression = match config.worker.as_ref().and_then(|w| w.log_compression) {
+        Some(compression) => compression,
+        None => Compression::Gzip,
+    };
+    let content =
+        tokio::task::spawn_blocking(move || storage::compress(log.as_bytes(), compression))
+            .await??;
+    let log_path = work_dir.join(format!("output.log{}", compression.extension()));
+    tokio::fs::write(&log_path, content).await?;
+
+    let object_name = format!("{}.log{}", request.execution_id, compression.extension());
+    let log = storage::Client::connect(minio_config, storage::Objects::Logs)?
+        .put(&log_path, &object_name)
+        .await?;
+
+    Ok(Outcome {
+        exit_code: status.code(),
+        timed_out,
+        log,
+        log_size,
+        log_compression: compression,
+    })
+}
+
+// Copies the script of the request to `path`, from the cache when it has
+// it, and checks its digest
+async fn fetch_script(
+    minio_config: &Minio,
+    cache: &SharedCache,
+    request: &ExecutionJobRequest,
+    path: &Path,
+) -> Result<(), anyhow::Error> {
+    let key = cache::key(&request.location, &request.script_digest);
+    let cached = {
+        let mut cache = cache.lock().unwrap();
+        match cache.enabled() {
+            true => cache.get(&key),
+            false => None,
+        }
+    };
+
+    // the copy fails if the entry was evicted in the meantime
+    let from_cache = match cached {
+        Some(cached) => tokio::fs::copy(&cached, path).await.is_ok(),
+        None => false,
+    };
+    if !from_cache {
+        let found = storage::Client::connect(minio_config, storage::Objects::Scripts)?
+            .get_to_file(&request.location, path)
+            .await?;
+        if !found {
+            return Err(anyhow::anyhow!("Script {} not found", request.location));
+        }
+    }
+
+    // jobs created before scripts had a digest are run unverified
+    if !request.script_digest.is_empty() {
+        let digest = storage::digest(&tokio::fs::read(path).await?);
+        if digest != request.script_digest {
+            if from_cache {
+                cache.lock().unwrap().remove(&key);
+            }
+            return Err(anyhow::anyhow!(
+                "Script {} has digest {}, expected {}",
+                request.location,
+                digest,
+                request.script_digest
+            ));
+        }
+    }
+
+    if !from_cache {
+        let temp_path = {
+            let cache = cache.lock().unwrap();
+            match cache.enabled() {
+                true => Some(cache.temp_path(&key, &request.execution_id)),
+                false => None,
+            }
+        };
+        // the execution does not need the cache, errors are only logged
+        if let Some(temp_path) = temp_path {
+            let cached = match tokio::fs::copy(path, &temp_path).await {
+                Ok(_) => cache.lock().unwrap().insert(&key, &temp_path),
+                Err(error) => Err(error),
+            };
+            if let Err(error) = cached {
+                println!("could not cache script {}: {:?}", request.location, error);
+                let _ = tokio::fs::remove_file(&temp_path).await;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+async fn collect<R: AsyncRead + Unpin>(reader: Option<R>, running: Running, id: String) {
+    let reader = match reader {
+        Some(reader) => reader,
+        None => return,
+    };
+
+    // read as bytes, scripts can print anything and the pipe has to be
+    // drained until the end or the script gets a SIGPIPE
+    let mut reader = reader;
+    let mut chunk = vec![0; CHUNK_SIZE];
+    let mut splitter = LineSplitter::default();
+    loop {
+        let read = match reader.read(&mut chunk).await {
+            Ok(0) | Err(_) => break,
+            Ok(read) => read,
+        };
+        for (line, written) in splitter.push(&chunk[..read]) {
+            record_line(&running, &id, line, written);
+        }
+    }
+    if let Some((line, written)) = splitter.finish() {
+        record_line(&running, &id, line, written);
+    }
+}
+
+fn record_line(running: &Running, id: &str, line: String, written: u64) {
+    if let Some(execution) = running.lock().unwrap().get_mut(id) {
+        // an error only means nobody is tailing it
+        let _ = execution.lines.send(line.clone());
+        execution.output.push(line, written);
+    }
+}
+
+// Splits the output of the script in lines as it is read. Past
+// MAX_LINE_SIZE the rest of a line is dropped and counted.
+#[derive(Default)]
+struct LineSplitter {
+    line: Vec<u8>,
+    dropped: u64,
+}
+
+impl LineSplitter {
+    // The lines completed by the chunk, with how many bytes were
+    // written for each one
+    fn push(&mut self, chunk: &[u8]) -> Vec<(String, u64)> {
+        let mut lines = vec![];
+        for part in chunk.split_inclusive(|byte| *byte == b'\n') {
+            let (content, newline) = match part.split_last() {
+                Some((b'\n', content)) => (content, true),
+                _ => (part, false),
+            };
+            let kept = content.len().min(MAX_LINE_SIZE - self.line.len());
+            self.line.extend_from_slice(&content[..kept]);
+            self.dropped += (content.len() - kept) as u64;
+            if newline {
+                lines.push(self.take(1));
+            }
+        }
+        lines
+    }
+
+    // The last line when the script did not end it with a newline
+    fn finish(&mut self) -> Option<(String, u64)> {
+        if self.line.is_empty() && self.dropped == 0 {
+            return None;
+        }
+        Some(self.take(0))
+    }
+
+    fn take(&mut self, newline: u64) -> (String, u64) {
+        let written = self.line.len() as u64 + self.dropped + newline;
+        let mut line = String::from_utf8_lossy(&self.line).into_owned();
+        if self.dropped > 0 {
+            line.push_str(&format!(" [{} bytes truncated]", self.dropped));
+        }
+        line.push('\n');
+
+        self.line.clear();
+        self.dropped = 0;
+        (line, written)
+    }
+}
+
+fn interpreter(job_type: i32) -> Result<&'static str, anyhow::Error> {
+    match ScriptType::from_i32(job_type) {
+        Some(ScriptType::Python) => Ok("python3"),
+        Some(ScriptType::Ruby) => Ok("ruby"),
+        Some(ScriptType::Shell) => Ok("sh"),
+        None => Err(anyhow::anyhow!("Script type {} not supported", job_type)),
+    }
+}
+
+fn log_max_size(config: &Config) -> u64 {
+    match config.worker.as_ref().and_then(|w| w.log_max_size) {
+        Some(size) => size * 1024 * 1024,
+        None => DEFAULT_LOG_MAX_SIZE,
+    }
+}
+
+// The id names the work dir of the execution, which is removed once it
+// finishes, and its files in the cache. Only ids such as the ones the
+// scheduler makes are accepted, so it cannot name another directory.
+fn check_execution_id(execution_id: &str) -> Result<(), Error> {
+    match mongodb::bson::oid::ObjectId::parse_str(execution_id) {
+        Ok(_) => Ok(()),
+        Err(_) => Err(Error::invalid(
+            "execution_id",
+            "must be an ObjectId of 24 hex characters",
+        )),
+    }
+}
+
+fn work_dir(config: &Config, execution_id: &str) -> PathBuf {
+    let base = match config.worker.as_ref().and_then(|w| w.work_dir.as_ref()) {
+        Some(work_dir) => PathBuf::from(work_dir),
+        None => std::env::temp_dir().join("dcron"),
+    };
+    base.join(execution_id)
+}
+
+fn kill_group(pgid: i32) {
+    // negative pid means the whole process group
+    unsafe {
+        libc::kill(-pgid, libc::SIGKILL);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn execution_ids_are_object_ids() {
+        assert!(check_execution_id("5f1d7c3e8a9b4c2d1e0f3a4b").is_ok());
+
+        for id in [
+            "",
+            "..",
+            "../../etc",
+            "5f1d7c3e8a9b4c2d1e0f3a4b/..",
+            "5f1d7c3e8a9b4c2d1e0f3a4",
+            "zzzzzzzzzzzzzzzzzzzzzzzz",
+        ] {
+            let result = check_execution_id(id);
+            assert!(
+                matches!(result, Err(Error::InvalidArgument(_))),
+                "{:?} was accepted",
+                id
+            );
+        }
+    }
+
+    fn push_all(output: &mut Output, lines: &[&str]) {
+        for line in lines {
+            output.push(line.to_string(), line.len() as u64);
+        }
+    }
+
+    #[test]
+    fn output_keeps_everything_under_the_cap() {
+        let mut output = Output::new(100);
+        push_all(&mut output, &["a\n", "b\n", "c\n"]);
+
+        assert_eq!(output.lines().concat(), "a\nb\nc\n");
+        assert_eq!(output.size, 6);
+    }
+
+    #[test]
+    fn output_keeps_head_and_tail() {
+        let mut output = Output::new(8);
+        push_all(
+            &mut output,
+            &["1\n", "2\n", "3\n", "4\n", "5\n", "6\n", "7\n"],
+        );
+
+        assert_eq!(output.lines().concat(), "1\n2\n[6 bytes truncated]\n6\n7\n");
+        assert_eq!(output.size, 14);
+    }
+
+    #[test]
+    fn output_without_cap() {
+        let mut output = Output::new(0);
+        push_all(&mut output, &["1\n"; 100]);
+
+        assert_eq!(output.lines().len(), 100);
+    }
+
+    #[test]
+    fn splitter_joins_lines_across_chunks() {
+        let mut splitter = LineSplitter::default();
+
+        assert_eq!(splitter.push(b"fir"), vec![]);
+        assert_eq!(
+            splitter.push(b"st\nsecond\nth"),
+            vec![("first\n".to_string(), 6), ("second\n".to_string(), 7)]
+        );
+        assert_eq!(splitter.finish(), Some(("th\n".to_string(), 2)));
+        assert_eq!(splitter.finish(), None);
+    }
+
+    #[test]
+    fn splitter_decodes_invalid_utf8() {
+        let mut splitter = LineSplitter::default();
+
+        assert_eq!(
+            splitter.push(b"a\xffb\n"),
+            vec![("a\u{fffd}b\n".to_string(), 4)]
+        );
+    }
+
+    #[test]
+    fn splitter_cuts_long_lines() {
+        let mut splitter = LineSplitter::default();
+        let long = vec![b'x'; MAX_LINE_SIZE + 10];
+
+        assert_eq!(splitter.push(&long), vec![]);
+        let lines = splitter.push(b"\nnext\n");
+        assert_eq!(lines.len(), 2);
+        assert_eq!(
+            lines[0].0.len(),
+            MAX_LINE_SIZE + " [10 bytes truncated]\n".len()
+        );
+        assert!(lines[0].0.ends_with("x [10 bytes truncated]\n"));
+        assert_eq!(lines[0].1, MAX_LINE_SIZE as u64 + 11);
+        assert_eq!(lines[1], ("next\n".to_string(), 5));
+    }
+}